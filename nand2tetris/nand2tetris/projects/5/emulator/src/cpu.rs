@@ -0,0 +1,249 @@
+// Words of RAM addressable with 15 bits, RAM proper is the first 16K
+pub const MEMORY_SIZE: usize = 32768;
+pub const SCREEN: u16 = 16384;
+pub const SCREEN_WORDS: usize = 8192;
+pub const KBD: u16 = 24576;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    // reached a `(END) @END 0;JMP` loop that can never change state again
+    Halted,
+    // ran max_cycles without halting
+    CycleLimit,
+    // a traced or profiled run hit an illegal state, see Tracer::fault
+    Fault,
+}
+
+// What one step did, for tracing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub pc: u16,
+    pub word: u16,
+    // address and value of a store to memory, made or ignored
+    pub write: Option<(u16, u16)>,
+}
+
+// The Hack CPU with its ROM and data memory. Each step is one clock cycle
+// and follows CPU.hdl: M is read and written at the A of the instruction's
+// start and a jump goes to that same A.
+#[derive(Debug, Clone)]
+pub struct Cpu {
+    a: u16,
+    d: u16,
+    pc: u16,
+    ram: Vec<u16>,
+    rom: Vec<u16>,
+    cycles: u64,
+    // run 101 prefixed words as the extended ISA shifts
+    extended: bool,
+}
+
+impl Cpu {
+    pub fn new(rom: Vec<u16>) -> Self {
+        Self {
+            a: 0,
+            d: 0,
+            pc: 0,
+            ram: vec![0; MEMORY_SIZE],
+            rom,
+            cycles: 0,
+            extended: false,
+        }
+    }
+
+    pub fn set_extended(&mut self, extended: bool) {
+        self.extended = extended;
+    }
+
+    pub fn extended(&self) -> bool {
+        self.extended
+    }
+
+    // Like the reset pin, only PC changes
+    pub fn reset(&mut self) {
+        self.pc = 0;
+    }
+
+    pub fn step(&mut self) -> Step {
+        let pc = self.pc;
+        let word = self.fetch(pc);
+        let mut step = Step {
+            pc,
+            word,
+            write: None,
+        };
+        self.cycles += 1;
+        if word & 0x8000 == 0 {
+            self.a = word;
+            self.pc = self.pc.wrapping_add(1);
+            return step;
+        }
+
+        let addr = self.a;
+        let y = if word & 0x1000 != 0 {
+            self.read(addr)
+        } else {
+            addr
+        };
+        let out = if self.extended && word >> 13 == 0b101 {
+            shift(word, self.d, y)
+        } else {
+            alu(self.d, y, word >> 6)
+        };
+        if word & 0b001000 != 0 {
+            self.store(addr, out);
+            step.write = Some((addr & 0x7FFF, out));
+        }
+        if word & 0b100000 != 0 {
+            self.a = out;
+        }
+        if word & 0b010000 != 0 {
+            self.d = out;
+        }
+        self.pc = if jumps(word, out) {
+            addr
+        } else {
+            self.pc.wrapping_add(1)
+        };
+        step
+    }
+
+    // Steps until the program halts or max_cycles more cycles have run
+    pub fn run(&mut self, max_cycles: u64) -> Stop {
+        for _ in 0..max_cycles {
+            if self.is_halted() {
+                return Stop::Halted;
+            }
+            self.step();
+        }
+        if self.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::CycleLimit
+        }
+    }
+
+    // true at either instruction of `(END) @END 0;JMP`, or at a jump to itself
+    pub fn is_halted(&self) -> bool {
+        let word = self.fetch(self.pc);
+        let is_loop_jump = |w: u16| w & 0x8000 != 0 && w & 0b111_111 == 0b000_111;
+        if word & 0x8000 == 0 {
+            return word == self.pc && is_loop_jump(self.fetch(self.pc.wrapping_add(1)));
+        }
+        is_loop_jump(word)
+            && (self.a == self.pc
+                || (self.a == self.pc.wrapping_sub(1) && self.fetch(self.a) == self.a))
+    }
+
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_a(&mut self, value: u16) {
+        self.a = value;
+    }
+
+    pub fn set_d(&mut self, value: u16) {
+        self.d = value;
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // For a run restored from a snapshot
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    // Swaps the program, leaving registers and RAM alone
+    pub fn set_rom(&mut self, rom: Vec<u16>) {
+        self.rom = rom;
+    }
+
+    // Only the low 15 bits of an address are wired to memory
+    pub fn read(&self, addr: u16) -> u16 {
+        self.ram[addr as usize & 0x7FFF]
+    }
+
+    // Sets any word, including the keyboard, the program itself can't
+    pub fn write(&mut self, addr: u16, value: u16) {
+        self.ram[addr as usize & 0x7FFF] = value;
+    }
+
+    pub fn ram(&self) -> &[u16] {
+        &self.ram
+    }
+
+    pub fn screen(&self) -> &[u16] {
+        &self.ram[SCREEN as usize..SCREEN as usize + SCREEN_WORDS]
+    }
+
+    pub fn key(&self) -> u16 {
+        self.ram[KBD as usize]
+    }
+
+    pub fn set_key(&mut self, key: u16) {
+        self.ram[KBD as usize] = key;
+    }
+
+    // ROM past the loaded program reads as 0, an @0
+    fn fetch(&self, addr: u16) -> u16 {
+        self.rom.get(addr as usize & 0x7FFF).copied().unwrap_or(0)
+    }
+
+    // Writes from the program, the keyboard and unmapped space ignore them
+    pub(crate) fn store(&mut self, addr: u16, value: u16) {
+        if addr & 0x7FFF < KBD {
+            self.write(addr, value);
+        }
+    }
+}
+
+// c holds zx nx zy ny f no in its low six bits
+pub(crate) fn alu(x: u16, y: u16, c: u16) -> u16 {
+    let x = if c & 0b100000 != 0 { 0 } else { x };
+    let x = if c & 0b010000 != 0 { !x } else { x };
+    let y = if c & 0b001000 != 0 { 0 } else { y };
+    let y = if c & 0b000100 != 0 { !y } else { y };
+    let out = if c & 0b000010 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if c & 0b000001 != 0 { !out } else { out }
+}
+
+// Extended ISA: the first c bit picks left over right (arithmetic) shift
+// and the second shifts D instead of A or M
+pub(crate) fn shift(word: u16, d: u16, y: u16) -> u16 {
+    let value = if word & 0x0400 != 0 { d } else { y };
+    if word & 0x0800 != 0 {
+        value << 1
+    } else {
+        ((value as i16) >> 1) as u16
+    }
+}
+
+pub(crate) fn jumps(word: u16, out: u16) -> bool {
+    let out = out as i16;
+    (word & 0b100 != 0 && out < 0)
+        || (word & 0b010 != 0 && out == 0)
+        || (word & 0b001 != 0 && out > 0)
+}
@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+
+use assembler::instruction::Instruction;
+use assembler::instruction::Jump;
+use assembler::symbols::SymbolMap;
+
+use crate::cpu::Cpu;
+use crate::loader::Loaded;
+use crate::parse_value;
+
+// cycles a continue or next may run before handing control back
+const RUN_LIMIT: u64 = 50_000_000;
+// where the VM stack starts
+const STACK_BASE: u16 = 256;
+
+const HELP: &str = "\
+step [N]        s   run N instructions, default 1
+next            n   like step but runs a jump through to the next address,
+                    stepping over a VM call until it returns
+continue        c   run until a breakpoint, watchpoint or the program halts
+break [LOC]     b   stop at a ROM address or label, without LOC list them
+delete [LOC]    d   remove a breakpoint, without LOC remove them all
+watch [ADDR]    w   stop when a RAM word like SP or a variable changes,
+                    without ADDR list them
+unwatch [ADDR]      remove a watchpoint, without ADDR remove them all
+regs            r   show A, D, M, PC and the cycle count
+x ADDR [N]          show N words of RAM from ADDR, default 8
+stack               show the VM pointers and the top of the stack
+list [LOC]      l   show the code around LOC, default the PC
+set NAME VALUE      set A, D, PC or a RAM word
+reset               jump back to ROM 0, RAM is kept
+quit            q   leave
+An empty line repeats the last command.
+";
+
+// Why a run handed control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stopped {
+    Breakpoint(u16),
+    Watchpoint { addr: u16, old: u16, new: u16 },
+    Halted,
+    // the step count or a next's return was reached
+    Done,
+    Limit,
+}
+
+// A terminal debugger over a loaded program. Commands come from any reader
+// so a session can be scripted by piping one in.
+pub struct Debugger {
+    cpu: Cpu,
+    // `file:line  text` per ROM address, .hack programs are disassembled
+    source: Vec<String>,
+    symbols: SymbolMap,
+    rom_names: HashMap<u16, String>,
+    ram_names: HashMap<u16, String>,
+    breakpoints: Vec<u16>,
+    // address and the value it had when last looked at
+    watchpoints: Vec<(u16, u16)>,
+}
+
+impl Debugger {
+    pub fn new(program: Loaded, extended: bool) -> Self {
+        let mut cpu = Cpu::new(program.words);
+        cpu.set_extended(extended);
+
+        // first name in sorted order wins, the VM pointers before R0-R4
+        let mut ram_names: HashMap<u16, String> = HashMap::new();
+        for (i, name) in ["SP", "LCL", "ARG", "THIS", "THAT"].iter().enumerate() {
+            ram_names.insert(i as u16, name.to_string());
+        }
+        for i in 5..16 {
+            ram_names.insert(i, format!("R{}", i));
+        }
+        ram_names.insert(16384, "SCREEN".to_string());
+        ram_names.insert(24576, "KBD".to_string());
+        let mut variables: Vec<(&String, &u16)> = program.symbols.variables.iter().collect();
+        variables.sort();
+        for (name, addr) in variables {
+            ram_names.entry(*addr).or_insert_with(|| name.clone());
+        }
+        let mut labels: Vec<(&String, &u16)> = program.symbols.labels.iter().collect();
+        labels.sort();
+        let mut rom_names: HashMap<u16, String> = HashMap::new();
+        for (name, addr) in labels {
+            rom_names.entry(*addr).or_insert_with(|| name.clone());
+        }
+
+        Self {
+            cpu,
+            source: program.source,
+            symbols: program.symbols,
+            rom_names,
+            ram_names,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    // Reads commands until quit or the end of the input
+    pub fn repl<R: BufRead, W: Write>(&mut self, mut input: R, out: &mut W) -> io::Result<()> {
+        self.show_location(out)?;
+        let mut last = String::new();
+        loop {
+            write!(out, "(hack) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            if !line.trim().is_empty() {
+                last = line.trim().to_string();
+            }
+            let command = last.clone();
+            let words: Vec<&str> = command.split_whitespace().collect();
+            let result = match words[..] {
+                [] => Ok(()),
+                ["quit" | "q"] => return Ok(()),
+                ["help" | "h"] => write!(out, "{}", HELP),
+                ["step" | "s"] => self.step(1, out),
+                ["step" | "s", count] => match count.parse::<u64>() {
+                    Ok(count) => self.step(count, out),
+                    Err(_) => writeln!(out, "error: expected a step count, found `{}`", count),
+                },
+                ["next" | "n"] => self.next(out),
+                ["continue" | "c"] => {
+                    let stopped = self.run_until(RUN_LIMIT, |_| false);
+                    self.report(stopped, out)
+                }
+                ["break" | "b"] => self.list_breakpoints(out),
+                ["break" | "b", loc] => self.add_breakpoint(loc, out),
+                ["delete" | "d"] => {
+                    self.breakpoints.clear();
+                    writeln!(out, "all breakpoints removed")
+                }
+                ["delete" | "d", loc] => self.delete_breakpoint(loc, out),
+                ["watch" | "w"] => self.list_watchpoints(out),
+                ["watch" | "w", addr] => self.add_watchpoint(addr, out),
+                ["unwatch"] => {
+                    self.watchpoints.clear();
+                    writeln!(out, "all watchpoints removed")
+                }
+                ["unwatch", addr] => self.delete_watchpoint(addr, out),
+                ["regs" | "r"] => self.show_registers(out),
+                ["x", addr] => self.dump(addr, "8", out),
+                ["x", addr, count] => self.dump(addr, count, out),
+                ["stack"] => self.show_stack(out),
+                ["list" | "l"] => self.list(self.cpu.pc(), out),
+                ["list" | "l", loc] => match self.rom_address(loc) {
+                    Some(addr) => self.list(addr, out),
+                    None => writeln!(out, "error: unknown ROM address or label `{}`", loc),
+                },
+                ["set", name, value] => self.set(name, value, out),
+                ["reset"] => {
+                    self.cpu.reset();
+                    self.show_location(out)
+                }
+                _ => writeln!(out, "error: unknown command `{}`, try `help`", command),
+            };
+            result?;
+        }
+    }
+
+    fn step(&mut self, count: u64, out: &mut impl Write) -> io::Result<()> {
+        let stopped = self.run_until(count, |_| false);
+        let stopped = if stopped == Stopped::Limit {
+            Stopped::Done
+        } else {
+            stopped
+        };
+        self.report(stopped, out)
+    }
+
+    // A jump is run until the address after it comes up with the stack no
+    // deeper than it is now, which is where a VM call returns to
+    fn next(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.cpu.pc();
+        let is_jump = matches!(
+            self.cpu.rom().get(pc as usize).and_then(|w| Instruction::decode(*w)),
+            Some(Instruction::C { jump, .. }) if jump != Jump::Null
+        );
+        if !is_jump {
+            return self.step(1, out);
+        }
+        let sp = self.cpu.read(0);
+        let ret = pc.wrapping_add(1);
+        let stopped = self.run_until(RUN_LIMIT, |cpu| cpu.pc() == ret && cpu.read(0) <= sp);
+        self.report(stopped, out)
+    }
+
+    // Steps until done says so, a breakpoint or watchpoint hits, the
+    // program halts or limit instructions have run
+    fn run_until(&mut self, limit: u64, done: impl Fn(&Cpu) -> bool) -> Stopped {
+        for _ in 0..limit {
+            if self.cpu.is_halted() {
+                return Stopped::Halted;
+            }
+            self.cpu.step();
+            if let Some(stopped) = self.check_watchpoints() {
+                return stopped;
+            }
+            if done(&self.cpu) {
+                return Stopped::Done;
+            }
+            if self.breakpoints.contains(&self.cpu.pc()) {
+                return Stopped::Breakpoint(self.cpu.pc());
+            }
+        }
+        Stopped::Limit
+    }
+
+    // Updates every watched value and reports the first that changed
+    fn check_watchpoints(&mut self) -> Option<Stopped> {
+        let mut stopped = None;
+        for (addr, last) in &mut self.watchpoints {
+            let now = self.cpu.read(*addr);
+            if now != *last {
+                stopped = stopped.or(Some(Stopped::Watchpoint {
+                    addr: *addr,
+                    old: *last,
+                    new: now,
+                }));
+                *last = now;
+            }
+        }
+        stopped
+    }
+
+    fn report(&self, stopped: Stopped, out: &mut impl Write) -> io::Result<()> {
+        match stopped {
+            Stopped::Breakpoint(addr) => writeln!(out, "breakpoint at {}", self.rom_name(addr))?,
+            Stopped::Watchpoint { addr, old, new } => writeln!(
+                out,
+                "{} changed from {} to {}",
+                self.ram_name(addr),
+                old as i16,
+                new as i16
+            )?,
+            Stopped::Halted => writeln!(out, "program halted after {} cycles", self.cpu.cycles())?,
+            Stopped::Limit => writeln!(
+                out,
+                "still running after {} cycles, `continue` to go on",
+                RUN_LIMIT
+            )?,
+            Stopped::Done => {}
+        }
+        self.show_location(out)
+    }
+
+    fn show_location(&self, out: &mut impl Write) -> io::Result<()> {
+        let pc = self.cpu.pc();
+        if let Some(name) = self.rom_names.get(&pc) {
+            writeln!(out, "({})", name)?;
+        }
+        writeln!(out, "=> {:5}  {}", pc, self.describe(pc))
+    }
+
+    fn list(&self, around: u16, out: &mut impl Write) -> io::Result<()> {
+        let from = around.saturating_sub(5);
+        let to = around.saturating_add(5).min(self.cpu.rom().len() as u16);
+        for addr in from..to {
+            if let Some(name) = self.rom_names.get(&addr) {
+                writeln!(out, "({})", name)?;
+            }
+            let marker = if addr == self.cpu.pc() { "=>" } else { "  " };
+            let stop = if self.breakpoints.contains(&addr) {
+                '*'
+            } else {
+                ' '
+            };
+            writeln!(out, "{}{}{:5}  {}", marker, stop, addr, self.describe(addr))?;
+        }
+        Ok(())
+    }
+
+    // The source line, or the disassembled word with the name its A
+    // instruction most likely refers to
+    fn describe(&self, addr: u16) -> String {
+        if let Some(line) = self.source.get(addr as usize) {
+            return line.clone();
+        }
+        let rom = self.cpu.rom();
+        let Some(word) = rom.get(addr as usize) else {
+            return "(past the end of the program)".to_string();
+        };
+        match Instruction::decode(*word) {
+            Some(Instruction::A { value, .. }) => {
+                let jumps_next = matches!(
+                    rom.get(addr as usize + 1).and_then(|w| Instruction::decode(*w)),
+                    Some(Instruction::C { jump, .. }) if jump != Jump::Null
+                );
+                let name = if jumps_next {
+                    self.rom_names.get(&value)
+                } else {
+                    self.ram_names.get(&value)
+                };
+                match name {
+                    Some(name) => format!("@{}  // {}", value, name),
+                    None => format!("@{}", value),
+                }
+            }
+            Some(instruction) => instruction.to_string(),
+            None => format!("// undecodable word {:016b}", word),
+        }
+    }
+
+    fn show_registers(&self, out: &mut impl Write) -> io::Result<()> {
+        let a = self.cpu.a();
+        let name = match self.ram_names.get(&a) {
+            Some(name) => format!(" ({})", name),
+            None => String::new(),
+        };
+        writeln!(
+            out,
+            "A: {}{}  D: {}  M: {}  PC: {}  cycles: {}",
+            a,
+            name,
+            self.cpu.d() as i16,
+            self.cpu.read(a) as i16,
+            self.cpu.pc(),
+            self.cpu.cycles()
+        )
+    }
+
+    fn dump(&self, addr: &str, count: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(from) = self.ram_address(addr) else {
+            return writeln!(out, "error: unknown RAM address or name `{}`", addr);
+        };
+        let Ok(count) = count.parse::<u16>() else {
+            return writeln!(out, "error: expected a word count, found `{}`", count);
+        };
+        for addr in from..from.saturating_add(count).min(0x8000) {
+            self.show_word(addr, out)?;
+        }
+        Ok(())
+    }
+
+    fn show_word(&self, addr: u16, out: &mut impl Write) -> io::Result<()> {
+        let name = self.ram_names.get(&addr).map(|n| n.as_str()).unwrap_or("");
+        writeln!(
+            out,
+            "{:5}  {:<12} {:6}",
+            addr,
+            name,
+            self.cpu.read(addr) as i16
+        )
+    }
+
+    // The frame pointers and up to 16 words below SP
+    fn show_stack(&self, out: &mut impl Write) -> io::Result<()> {
+        for addr in 0..5 {
+            self.show_word(addr, out)?;
+        }
+        let sp = self.cpu.read(0);
+        if !(STACK_BASE..0x8000).contains(&sp) {
+            return writeln!(out, "SP {} is outside the stack", sp as i16);
+        }
+        if sp == STACK_BASE {
+            return writeln!(out, "the stack is empty");
+        }
+        let from = sp.saturating_sub(16).max(STACK_BASE);
+        if from > STACK_BASE {
+            writeln!(out, "   ...")?;
+        }
+        for addr in from..sp {
+            writeln!(out, "{:5}  {:6}", addr, self.cpu.read(addr) as i16)?;
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(value) = parse_value(value) else {
+            return writeln!(out, "error: expected a value, found `{}`", value);
+        };
+        match name {
+            "A" => self.cpu.set_a(value),
+            "D" => self.cpu.set_d(value),
+            "PC" => self.cpu.set_pc(value),
+            _ => match self.ram_address(name) {
+                Some(addr) => self.cpu.write(addr, value),
+                None => return writeln!(out, "error: unknown register or RAM address `{}`", name),
+            },
+        }
+        // a change made by hand shouldn't trip a watchpoint
+        for (addr, last) in &mut self.watchpoints {
+            *last = self.cpu.read(*addr);
+        }
+        Ok(())
+    }
+
+    fn add_breakpoint(&mut self, loc: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(addr) = self.rom_address(loc) else {
+            return writeln!(out, "error: unknown ROM address or label `{}`", loc);
+        };
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+        writeln!(out, "breakpoint at {}", self.rom_name(addr))
+    }
+
+    fn delete_breakpoint(&mut self, loc: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(addr) = self.rom_address(loc) else {
+            return writeln!(out, "error: unknown ROM address or label `{}`", loc);
+        };
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != addr);
+        if self.breakpoints.len() == before {
+            return writeln!(out, "error: no breakpoint at {}", self.rom_name(addr));
+        }
+        writeln!(out, "removed the breakpoint at {}", self.rom_name(addr))
+    }
+
+    fn list_breakpoints(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(out, "no breakpoints");
+        }
+        for addr in &self.breakpoints {
+            writeln!(out, "{}", self.rom_name(*addr))?;
+        }
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, addr: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(addr) = self.ram_address(addr) else {
+            return writeln!(out, "error: unknown RAM address or name `{}`", addr);
+        };
+        if !self.watchpoints.iter().any(|(a, _)| *a == addr) {
+            self.watchpoints.push((addr, self.cpu.read(addr)));
+        }
+        writeln!(
+            out,
+            "watching {} = {}",
+            self.ram_name(addr),
+            self.cpu.read(addr) as i16
+        )
+    }
+
+    fn delete_watchpoint(&mut self, addr: &str, out: &mut impl Write) -> io::Result<()> {
+        let Some(addr) = self.ram_address(addr) else {
+            return writeln!(out, "error: unknown RAM address or name `{}`", addr);
+        };
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|(a, _)| *a != addr);
+        if self.watchpoints.len() == before {
+            return writeln!(out, "error: {} isn't watched", self.ram_name(addr));
+        }
+        writeln!(out, "stopped watching {}", self.ram_name(addr))
+    }
+
+    fn list_watchpoints(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.watchpoints.is_empty() {
+            return writeln!(out, "no watchpoints");
+        }
+        for (addr, _) in &self.watchpoints {
+            self.show_word(*addr, out)?;
+        }
+        Ok(())
+    }
+
+    // A number or a label
+    fn rom_address(&self, loc: &str) -> Option<u16> {
+        if let Ok(addr) = loc.parse::<u16>() {
+            return Some(addr).filter(|a| *a < 0x8000);
+        }
+        self.symbols.labels.get(loc).copied()
+    }
+
+    // A number, a predefined name like SP or R13, or a variable
+    fn ram_address(&self, name: &str) -> Option<u16> {
+        crate::parse_address(name).or_else(|| self.symbols.variables.get(name).copied())
+    }
+
+    // `23 (LOOP)` or just `23`
+    fn rom_name(&self, addr: u16) -> String {
+        match self.rom_names.get(&addr) {
+            Some(name) => format!("{} ({})", addr, name),
+            None => addr.to_string(),
+        }
+    }
+
+    fn ram_name(&self, addr: u16) -> String {
+        match self.ram_names.get(&addr) {
+            Some(name) => format!("{} (RAM[{}])", name, addr),
+            None => format!("RAM[{}]", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use crate::loader::load_program;
+
+    #[test]
+    fn a_scripted_session() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../../8/FunctionCalls/NestedCall/NestedCall.asm");
+        let program = load_program(&path).unwrap();
+        let init = program.symbols.labels["Sys.init"];
+        let ret = program.symbols.labels["Sys.init$ret1"];
+        let mut debugger = Debugger::new(program, false);
+        // ret - 1 is the 0;JMP of Sys.init's call to Sys.main
+        let script = format!(
+            "break Sys.init\ncontinue\ndelete\nbreak {}\ncontinue\nnext\nwatch SP\ncontinue\n\
+             unwatch\ncontinue\n",
+            ret - 1
+        );
+        let mut out = Vec::new();
+        debugger.repl(script.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        // what each command printed, after the starting location
+        let replies: Vec<&str> = out.split("(hack) ").collect();
+
+        assert!(replies[1].starts_with(&format!("breakpoint at {} ", init)));
+        assert!(
+            replies[2].contains(&format!("=> {:5}", init)),
+            "{}",
+            replies[2]
+        );
+        assert_eq!(replies[3], "all breakpoints removed\n");
+        assert!(
+            replies[5].contains(&format!("=> {:5}", ret - 1)),
+            "{}",
+            replies[5]
+        );
+        // the whole of Sys.main runs and it stops on the return label
+        assert!(
+            replies[6].starts_with(&format!("(Sys.init$ret1)\n=> {:5}", ret)),
+            "{}",
+            replies[6]
+        );
+        assert!(
+            replies[7].starts_with("watching SP (RAM[0]) = "),
+            "{}",
+            replies[7]
+        );
+        assert!(
+            replies[8].starts_with("SP (RAM[0]) changed from "),
+            "{}",
+            replies[8]
+        );
+        assert_eq!(replies[9], "all watchpoints removed\n");
+        assert!(
+            replies[10].starts_with("program halted after "),
+            "{}",
+            replies[10]
+        );
+        assert!(replies[10].contains("(Sys.init$LOOP)"), "{}", replies[10]);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::cpu::Cpu;
+use crate::cpu::Stop;
+use crate::cpu::alu;
+use crate::cpu::jumps;
+use crate::cpu::shift;
+
+// block index for an address no block starts at yet
+const NONE: u32 = u32::MAX;
+
+// `@SP`, `AM=M+1`, `AM=M-1`, `A=A-1`, `A=M`, `M=D`, `D=M`, `D=A`, `M=M+1`
+// and `M=M-1` as words, the pieces of the stack code the VM translators write
+const AT_SP: u16 = 0;
+const AM_M_PLUS_1: u16 = 0b1111_1101_1110_1000;
+const AM_M_MINUS_1: u16 = 0b1111_1100_1010_1000;
+const A_A_MINUS_1: u16 = 0b1110_1100_1010_0000;
+const A_M: u16 = 0b1111_1100_0010_0000;
+const M_D: u16 = 0b1110_0011_0000_1000;
+const D_M: u16 = 0b1111_1100_0001_0000;
+const D_A: u16 = 0b1110_1100_0001_0000;
+const M_M_PLUS_1: u16 = 0b1111_1101_1100_1000;
+const M_M_MINUS_1: u16 = 0b1111_1100_1000_1000;
+
+// One or more instructions that don't jump. The fused ones leave A, D and
+// memory exactly as their instructions would.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    // @value
+    SetA(u16),
+    // any other C instruction without a jump
+    Compute(u16),
+    // @X D=A
+    LoadConstant(u16),
+    // @X D=M
+    Load(u16),
+    // @X M=D
+    Store(u16),
+    // @SP AM=M+1 A=A-1 M=D, the official translator's push
+    PushShort,
+    // @SP A=M M=D @SP M=M+1, our translator's push
+    PushLong,
+    // @SP AM=M-1 D=M, the official translator's pop
+    PopShort,
+    // @SP M=M-1 @SP A=M D=M, our translator's pop
+    PopLong,
+}
+
+impl Op {
+    fn words(&self) -> u64 {
+        match self {
+            Op::SetA(_) | Op::Compute(_) => 1,
+            Op::LoadConstant(_) | Op::Load(_) | Op::Store(_) => 2,
+            Op::PopShort => 3,
+            Op::PushShort => 4,
+            Op::PushLong | Op::PopLong => 5,
+        }
+    }
+}
+
+// Straight line code from one address up to and including its first jump
+#[derive(Debug, Clone)]
+struct Block {
+    ops: Vec<Op>,
+    // the C instruction with jump bits that ends the block and its address,
+    // or none when the block runs into the end of the ROM or a halt loop
+    jump: Option<(u16, u16)>,
+    // where execution carries on if the jump isn't taken
+    next: u16,
+    cycles: u64,
+}
+
+// Runs a program a basic block at a time instead of a word at a time. Each
+// block is decoded the first time execution reaches its address, with the
+// stack code the translators write fused into single steps, and is kept for
+// good since ROM can't change. It must only run CPUs holding the ROM it was
+// built from. Cycle counts, halting and memory come out the same as with
+// Cpu::run.
+pub struct Engine {
+    rom: Vec<u16>,
+    extended: bool,
+    // block index by start address
+    starts: Vec<u32>,
+    blocks: Vec<Block>,
+}
+
+impl Engine {
+    pub fn new(rom: &[u16], extended: bool) -> Self {
+        Self {
+            rom: rom.to_vec(),
+            extended,
+            starts: vec![NONE; rom.len()],
+            blocks: Vec::new(),
+        }
+    }
+
+    // Blocks decoded so far and how many of their ops are fused
+    pub fn stats(&self) -> (usize, usize) {
+        let fused = self
+            .blocks
+            .iter()
+            .flat_map(|block| &block.ops)
+            .filter(|op| op.words() > 1)
+            .count();
+        (self.blocks.len(), fused)
+    }
+
+    // Like Cpu::run, steps until the program halts or max_cycles more
+    // cycles have run. A block that would go past the limit is stepped
+    // through a word at a time.
+    pub fn run(&mut self, cpu: &mut Cpu, max_cycles: u64) -> Stop {
+        let until = cpu.cycles().saturating_add(max_cycles);
+        while cpu.cycles() < until {
+            if cpu.is_halted() {
+                return Stop::Halted;
+            }
+            let pc = cpu.pc();
+            let Some(index) = self.block(pc) else {
+                cpu.step();
+                continue;
+            };
+            let block = &self.blocks[index];
+            if block.cycles > until - cpu.cycles() {
+                cpu.step();
+                continue;
+            }
+            if !self.execute(index, cpu) {
+                return Stop::Halted;
+            }
+        }
+        if cpu.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::CycleLimit
+        }
+    }
+
+    // The block starting at `pc`, decoding it if need be. None past the end
+    // of the ROM, where Cpu::step runs the zeros.
+    fn block(&mut self, pc: u16) -> Option<usize> {
+        let start = *self.starts.get(pc as usize)?;
+        if start != NONE {
+            return Some(start as usize);
+        }
+        let block = self.decode(pc);
+        self.blocks.push(block);
+        self.starts[pc as usize] = (self.blocks.len() - 1) as u32;
+        Some(self.blocks.len() - 1)
+    }
+
+    fn decode(&self, pc: u16) -> Block {
+        let mut ops = Vec::new();
+        let mut at = pc as usize;
+        let mut cycles = 0;
+        loop {
+            // a halt loop starts its own block so run sees it, like
+            // Cpu::run checks before every step
+            if at >= self.rom.len() || (at != pc as usize && self.halt_loop(at)) {
+                return Block {
+                    ops,
+                    jump: None,
+                    next: at as u16,
+                    cycles,
+                };
+            }
+            let word = self.rom[at];
+            if word & 0x8000 != 0 && word & 0b111 != 0 {
+                return Block {
+                    ops,
+                    jump: Some((word, at as u16)),
+                    next: at as u16 + 1,
+                    cycles: cycles + 1,
+                };
+            }
+            let op = self.fuse(at);
+            at += op.words() as usize;
+            cycles += op.words();
+            ops.push(op);
+        }
+    }
+
+    // The longest op the words at `at` make
+    fn fuse(&self, at: usize) -> Op {
+        let words = &self.rom[at..self.rom.len().min(at + 5)];
+        match *words {
+            [AT_SP, A_M, M_D, AT_SP, M_M_PLUS_1] => return Op::PushLong,
+            [AT_SP, M_M_MINUS_1, AT_SP, A_M, D_M] => return Op::PopLong,
+            _ => {}
+        }
+        match words {
+            [AT_SP, AM_M_PLUS_1, A_A_MINUS_1, M_D, ..] => Op::PushShort,
+            [AT_SP, AM_M_MINUS_1, D_M, ..] => Op::PopShort,
+            [x, D_A, ..] if x & 0x8000 == 0 => Op::LoadConstant(*x),
+            [x, D_M, ..] if x & 0x8000 == 0 => Op::Load(*x),
+            [x, M_D, ..] if x & 0x8000 == 0 => Op::Store(*x),
+            [x, ..] if x & 0x8000 == 0 => Op::SetA(*x),
+            [word, ..] => Op::Compute(*word),
+            [] => unreachable!("decode stops at the end of the ROM"),
+        }
+    }
+
+    // `(END) @END 0;JMP`, see Cpu::is_halted
+    fn halt_loop(&self, at: usize) -> bool {
+        let loop_jump = |w: u16| w & 0x8000 != 0 && w & 0b111 == 0b111;
+        self.rom[at] as usize == at && self.rom.get(at + 1).is_some_and(|w| loop_jump(*w))
+    }
+
+    // Runs a whole block, false if it stopped at a jump to itself, which
+    // Cpu::run would count as halted without running it
+    fn execute(&self, index: usize, cpu: &mut Cpu) -> bool {
+        let block = &self.blocks[index];
+        let mut a = cpu.a();
+        let mut d = cpu.d();
+        for op in &block.ops {
+            match *op {
+                Op::SetA(value) => a = value,
+                Op::Compute(word) => {
+                    self.compute(cpu, word, &mut a, &mut d);
+                }
+                Op::LoadConstant(value) => {
+                    a = value;
+                    d = value;
+                }
+                Op::Load(addr) => {
+                    a = addr;
+                    d = cpu.read(addr);
+                }
+                Op::Store(addr) => {
+                    a = addr;
+                    cpu.store(addr, d);
+                }
+                Op::PushShort => {
+                    let sp = cpu.read(0).wrapping_add(1);
+                    cpu.store(0, sp);
+                    a = sp.wrapping_sub(1);
+                    cpu.store(a, d);
+                }
+                Op::PushLong => {
+                    let sp = cpu.read(0);
+                    cpu.store(sp, d);
+                    cpu.store(0, cpu.read(0).wrapping_add(1));
+                    a = 0;
+                }
+                Op::PopShort => {
+                    let sp = cpu.read(0).wrapping_sub(1);
+                    cpu.store(0, sp);
+                    a = sp;
+                    d = cpu.read(sp);
+                }
+                Op::PopLong => {
+                    let sp = cpu.read(0).wrapping_sub(1);
+                    cpu.store(0, sp);
+                    a = cpu.read(0);
+                    d = cpu.read(a);
+                }
+            }
+        }
+
+        let Some((word, at)) = block.jump else {
+            cpu.set_a(a);
+            cpu.set_d(d);
+            cpu.set_pc(block.next);
+            cpu.set_cycles(cpu.cycles() + block.cycles);
+            return true;
+        };
+        cpu.set_a(a);
+        cpu.set_d(d);
+        cpu.set_pc(at);
+        cpu.set_cycles(cpu.cycles() + block.cycles - 1);
+        if word & 0b111 == 0b111 && cpu.is_halted() {
+            return false;
+        }
+        let target = a;
+        let out = self.compute(cpu, word, &mut a, &mut d);
+        cpu.set_a(a);
+        cpu.set_d(d);
+        cpu.set_pc(if jumps(word, out) { target } else { block.next });
+        cpu.set_cycles(cpu.cycles() + 1);
+        true
+    }
+
+    // What Cpu::step does for a C instruction apart from the jump
+    fn compute(&self, cpu: &mut Cpu, word: u16, a: &mut u16, d: &mut u16) -> u16 {
+        let addr = *a;
+        let y = if word & 0x1000 != 0 {
+            cpu.read(addr)
+        } else {
+            addr
+        };
+        let out = if self.extended && word >> 13 == 0b101 {
+            shift(word, *d, y)
+        } else {
+            alu(*d, y, word >> 6)
+        };
+        if word & 0b001000 != 0 {
+            cpu.store(addr, out);
+        }
+        if word & 0b100000 != 0 {
+            *a = out;
+        }
+        if word & 0b010000 != 0 {
+            *d = out;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use crate::loader::load_rom;
+
+    const PROGRAMS: [&str; 13] = [
+        "../6/pong/Pong.asm",
+        "../7/StackArithmetic/SimpleAdd/SimpleAdd.asm",
+        "../7/StackArithmetic/StackTest/StackTest.asm",
+        "../7/MemoryAccess/BasicTest/BasicTest.asm",
+        "../7/MemoryAccess/PointerTest/PointerTest.asm",
+        "../7/MemoryAccess/StaticTest/StaticTest.asm",
+        "../8/ProgramFlow/BasicLoop/BasicLoop.asm",
+        "../8/ProgramFlow/FibonacciSeries/FibonacciSeries.asm",
+        "../8/FunctionCalls/SimpleFunction/SimpleFunction.asm",
+        "../8/FunctionCalls/NestedCall/NestedCall.asm",
+        "../8/FunctionCalls/FibonacciElement/FibonacciElement.asm",
+        "../8/FunctionCalls/StaticsTest/StaticsTest.asm",
+        "../4/mult/Mult.asm",
+    ];
+
+    // A CPU with the pointers the test scripts set up and a couple of
+    // arguments for the programs that read them
+    fn cpu(path: &str) -> Cpu {
+        let rom = load_rom(&Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join(path)).unwrap();
+        let mut cpu = Cpu::new(rom);
+        for (addr, value) in [256, 300, 400, 3000, 3010, 6, 7].into_iter().enumerate() {
+            cpu.write(addr as u16, value);
+        }
+        cpu.write(400, 4);
+        cpu.write(401, 6);
+        cpu
+    }
+
+    fn assert_same(path: &str, expected: &Cpu, actual: &Cpu) {
+        assert_eq!(expected.cycles(), actual.cycles(), "{} cycles", path);
+        assert_eq!(expected.pc(), actual.pc(), "{} PC", path);
+        assert_eq!(expected.a(), actual.a(), "{} A", path);
+        assert_eq!(expected.d(), actual.d(), "{} D", path);
+        assert!(expected.ram() == actual.ram(), "{} RAM differs", path);
+    }
+
+    #[test]
+    fn blocks_run_like_single_steps() {
+        for path in PROGRAMS {
+            let (mut expected, mut actual) = (cpu(path), cpu(path));
+            let mut engine = Engine::new(actual.rom(), false);
+            let stop = expected.run(300_000);
+            assert_eq!(engine.run(&mut actual, 300_000), stop, "{}", path);
+            assert_same(path, &expected, &actual);
+        }
+    }
+
+    #[test]
+    fn a_cycle_limit_can_stop_a_block_part_way() {
+        for path in PROGRAMS {
+            let (mut expected, mut actual) = (cpu(path), cpu(path));
+            let mut engine = Engine::new(actual.rom(), false);
+            // limits that don't line up with blocks, checked after each run
+            for limit in [1, 2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47] {
+                let stop = expected.run(limit);
+                assert_eq!(engine.run(&mut actual, limit), stop, "{}", path);
+                assert_same(path, &expected, &actual);
+            }
+        }
+    }
+
+    #[test]
+    fn stack_code_is_fused() {
+        let mut cpu = cpu("../6/pong/Pong.asm");
+        let mut engine = Engine::new(cpu.rom(), false);
+        engine.run(&mut cpu, 100_000);
+        let (blocks, fused) = engine.stats();
+        assert!(blocks > 0 && fused > 0);
+    }
+}
@@ -0,0 +1,687 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+
+use assembler::symbols::SymbolMap;
+
+use crate::cpu::Cpu;
+use crate::cpu::MEMORY_SIZE;
+use crate::loader::Loaded;
+
+// Byte address where ROM starts in gdb's memory space, RAM is at 0. Each
+// word is two bytes, low byte first, so RAM[n] is at 2n and ROM[n] at
+// ROM_BASE + 2n. $pc, breakpoints and the address `s` and `c` take are in the
+// same space, so `x/2xb $pc` shows the word about to run.
+pub const ROM_BASE: u32 = 0x10000;
+// cycles between checks for a ^C from the debugger while continuing
+const POLL_CYCLES: u64 = 1 << 16;
+
+// Registers a, d and pc in the order `g` packets carry them, pc is a byte
+// address in ROM so it needs 32 bits
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.nand2tetris.hack.core">
+    <reg name="a" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="d" bitsize="16" type="int16" regnum="1"/>
+    <reg name="pc" bitsize="32" type="code_ptr" regnum="2"/>
+  </feature>
+</target>
+"#;
+
+// RAM then ROM, 32K words each. gdb doesn't write breakpoints into ROM, it
+// sends Z packets for them.
+const MEMORY_MAP: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN"
+    "http://sourceware.org/gdb/gdb-memory-map.dtd">
+<memory-map>
+  <memory type="ram" start="0x0" length="0x10000"/>
+  <memory type="rom" start="0x10000" length="0x10000"/>
+</memory-map>
+"#;
+
+// bytes in a `g` packet: a, d and pc
+const REGISTER_BYTES: [usize; 3] = [2, 2, 4];
+
+const MONITOR_HELP: &str = "\
+symbol NAME     the ROM or RAM address of a label, variable or predefined name,
+                as a word and as gdb's byte address
+where ADDR      the label a ROM address is in, a word or a 0x byte address
+reset           jump back to ROM 0, RAM is kept
+";
+
+// SIGTRAP after a step or breakpoint, SIGINT after a ^C
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+
+// The byte stream to gdb
+pub trait Connection: Read + Write {
+    // true if gdb sent a ^C, without waiting for one
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let read = self.read(&mut byte);
+        let _ = self.set_nonblocking(false);
+        matches!(read, Ok(1)) && byte[0] == 0x03
+    }
+}
+
+// stdin and stdout, for `target remote | emulator gdb ...`. A ^C can't be
+// seen while the program runs.
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Connection for Stdio {}
+
+// Why the program handed control back to gdb
+enum Stopped {
+    Signal(u8),
+    // reached its (END) loop, reported as an exit
+    Halted,
+}
+
+// A GDB remote serial protocol stub for one program. It knows the packets
+// gdb needs to attach, read and write registers and memory, set software
+// breakpoints, step and continue, plus `monitor` commands for the labels.
+pub struct GdbStub<C: Connection> {
+    conn: C,
+    cpu: Cpu,
+    symbols: SymbolMap,
+    breakpoints: BTreeSet<u16>,
+    // after QStartNoAckMode neither side sends + or -
+    ack: bool,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(conn: C, program: Loaded, extended: bool) -> Self {
+        let mut cpu = Cpu::new(program.words);
+        cpu.set_extended(extended);
+        Self {
+            conn,
+            cpu,
+            symbols: program.symbols,
+            breakpoints: BTreeSet::new(),
+            ack: true,
+        }
+    }
+
+    // Answers packets until gdb detaches, kills the program or hangs up
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                }
+                _ => self.handle(&packet),
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+
+    // The reply to one packet, empty for ones that aren't supported
+    fn handle(&mut self, packet: &str) -> String {
+        if packet.is_empty() || !packet.is_char_boundary(1) {
+            return String::new();
+        }
+        let (command, args) = packet.split_at(1);
+        match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..3).map(|reg| self.register(reg)).collect(),
+            "G" => {
+                let Some(bytes) = unhex(args).filter(|b| b.len() == REGISTER_BYTES.iter().sum())
+                else {
+                    return "E01".to_string();
+                };
+                let mut values = Vec::new();
+                let mut at = 0;
+                for len in REGISTER_BYTES {
+                    values.push(le_value(&bytes[at..at + len]));
+                    at += len;
+                }
+                // all or nothing, a bad pc leaves a and d alone too
+                if rom_word(values[2]).is_none() {
+                    return "E01".to_string();
+                }
+                for (reg, value) in values.into_iter().enumerate() {
+                    self.set_register(reg, value);
+                }
+                "OK".to_string()
+            }
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(reg) if reg < 3 => self.register(reg),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(reg, value)| {
+                    let reg = usize::from_str_radix(reg, 16).ok().filter(|r| *r < 3)?;
+                    let bytes = unhex(value).filter(|b| b.len() == REGISTER_BYTES[reg])?;
+                    Some((reg, le_value(&bytes)))
+                });
+                match parsed {
+                    Some((reg, value)) if self.set_register(reg, value) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => {
+                let range = args.split_once(',').and_then(|(addr, len)| {
+                    Some((
+                        u32::from_str_radix(addr, 16).ok()?,
+                        u32::from_str_radix(len, 16).ok()?,
+                    ))
+                });
+                match range.and_then(|(addr, len)| self.read_memory(addr, len)) {
+                    Some(bytes) => hex(&bytes),
+                    None => "E01".to_string(),
+                }
+            }
+            "M" => {
+                let write = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = range.split_once(',')?;
+                    let addr = u32::from_str_radix(addr, 16).ok()?;
+                    let len = usize::from_str_radix(len, 16).ok()?;
+                    Some((addr, unhex(data).filter(|bytes| bytes.len() == len)?))
+                });
+                match write {
+                    Some((addr, bytes)) if self.write_memory(addr, &bytes) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "s" | "c" => {
+                if !args.is_empty() {
+                    match u32::from_str_radix(args, 16).ok().and_then(rom_word) {
+                        Some(addr) => self.cpu.set_pc(addr),
+                        None => return "E01".to_string(),
+                    }
+                }
+                let stopped = if command == "s" {
+                    self.cpu.step();
+                    Stopped::Signal(SIGTRAP)
+                } else {
+                    self.resume()
+                };
+                match stopped {
+                    Stopped::Signal(signal) => format!("S{:02x}", signal),
+                    Stopped::Halted => "W00".to_string(),
+                }
+            }
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;qXfer:memory-map:read+;QStartNoAckMode+"
+                .to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return xfer(TARGET_XML, range);
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:memory-map:read::") {
+            return xfer(MEMORY_MAP, range);
+        }
+        if let Some(lookup) = packet.strip_prefix("qSymbol:") {
+            return self.symbol_lookup(lookup);
+        }
+        if let Some(command) = packet.strip_prefix("qRcmd,") {
+            return match unhex(command) {
+                Some(bytes) => hex(self.monitor(&String::from_utf8_lossy(&bytes)).as_bytes()),
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // gdb still acks the OK, read_packet skips the +
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    // gdb sends `qSymbol::` once it can look up symbols for the stub, which
+    // needs none, and `qSymbol:VALUE:NAME` with a value it was asked for.
+    // Both are acknowledged with OK. `qSymbol::NAME`, a name with no value,
+    // is answered from the program's labels and variables as
+    // `qSymbol:VALUE:NAME` with the address in gdb's memory space, so
+    // `maint packet` and scripts can look names up. Numbers are hex and
+    // names hex encoded as in the protocol.
+    fn symbol_lookup(&self, lookup: &str) -> String {
+        let Some(("", name)) = lookup.split_once(':') else {
+            return "OK".to_string();
+        };
+        let Some(name) = unhex(name).and_then(|bytes| String::from_utf8(bytes).ok()) else {
+            return "OK".to_string();
+        };
+        let addr = if let Some(addr) = self.symbols.labels.get(&name) {
+            rom_byte(*addr)
+        } else if let Some(addr) = self
+            .symbols
+            .variables
+            .get(&name)
+            .or_else(|| self.symbols.predefined.get(&name))
+        {
+            2 * *addr as u32
+        } else {
+            return "OK".to_string();
+        };
+        format!("qSymbol:{:x}:{}", addr, hex(name.as_bytes()))
+    }
+
+    // `monitor` commands, the output goes to gdb's console
+    fn monitor(&mut self, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words[..] {
+            ["symbol", name] => {
+                if let Some(addr) = self.symbols.labels.get(name) {
+                    format!("{} = ROM {} (0x{:x})\n", name, addr, rom_byte(*addr))
+                } else if let Some(addr) = crate::parse_address(name)
+                    .filter(|_| name.parse::<u16>().is_err())
+                    .or_else(|| self.symbols.variables.get(name).copied())
+                {
+                    format!("{} = RAM {} (0x{:x})\n", name, addr, 2 * addr as u32)
+                } else {
+                    format!("no symbol `{}`\n", name)
+                }
+            }
+            ["where", text] => {
+                let addr = match text.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(rom_word),
+                    None => text.parse::<u16>().ok(),
+                };
+                match addr {
+                    Some(addr) => format!("{}\n", self.rom_name(addr)),
+                    None => format!("expected a ROM address, found `{}`\n", text),
+                }
+            }
+            ["reset"] => {
+                self.cpu.reset();
+                "PC is 0\n".to_string()
+            }
+            ["help"] | [] => MONITOR_HELP.to_string(),
+            _ => format!("unknown command `{}`, try `monitor help`\n", command),
+        }
+    }
+
+    // `Function.name+3` for the closest label at or before the address
+    fn rom_name(&self, addr: u16) -> String {
+        let closest = self
+            .symbols
+            .labels
+            .iter()
+            .filter(|(_, label)| **label <= addr)
+            .max_by(|(a_name, a), (b_name, b)| a.cmp(b).then(b_name.cmp(a_name)));
+        match closest {
+            Some((name, label)) if *label == addr => name.clone(),
+            Some((name, label)) => format!("{}+{}", name, addr - label),
+            None => addr.to_string(),
+        }
+    }
+
+    // Z0 and Z1 at a ROM address, the kind is ignored. Watchpoints aren't
+    // supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let ["0" | "1", addr, _kind] = fields[..] else {
+            return String::new();
+        };
+        let Some(addr) = u32::from_str_radix(addr, 16).ok().and_then(rom_word) else {
+            return "E01".to_string();
+        };
+        if insert {
+            self.breakpoints.insert(addr);
+        } else {
+            self.breakpoints.remove(&addr);
+        }
+        "OK".to_string()
+    }
+
+    // Runs until a breakpoint, the program halting or a ^C. The first
+    // instruction always runs so continuing from a breakpoint moves on.
+    fn resume(&mut self) -> Stopped {
+        loop {
+            for _ in 0..POLL_CYCLES {
+                if self.cpu.is_halted() {
+                    return Stopped::Halted;
+                }
+                self.cpu.step();
+                if self.breakpoints.contains(&self.cpu.pc()) {
+                    return Stopped::Signal(SIGTRAP);
+                }
+            }
+            if self.conn.interrupted() {
+                return Stopped::Signal(SIGINT);
+            }
+        }
+    }
+
+    // A register as the hex bytes `g` and `p` send
+    fn register(&self, reg: usize) -> String {
+        match reg {
+            0 => hex(&self.cpu.a().to_le_bytes()),
+            1 => hex(&self.cpu.d().to_le_bytes()),
+            _ => hex(&rom_byte(self.cpu.pc()).to_le_bytes()),
+        }
+    }
+
+    // false for a pc outside ROM or between two words
+    fn set_register(&mut self, reg: usize, value: u32) -> bool {
+        match reg {
+            0 => self.cpu.set_a(value as u16),
+            1 => self.cpu.set_d(value as u16),
+            _ => match rom_word(value) {
+                Some(addr) => self.cpu.set_pc(addr),
+                None => return false,
+            },
+        }
+        true
+    }
+
+    // None if the range runs off the end of RAM or ROM
+    fn read_memory(&self, addr: u32, len: u32) -> Option<Vec<u8>> {
+        let (words, offset): (&[u16], u32) = if addr >= ROM_BASE {
+            (self.cpu.rom(), addr - ROM_BASE)
+        } else {
+            (self.cpu.ram(), addr)
+        };
+        let end = offset.checked_add(len)?;
+        if end as usize > words.len() * 2 {
+            return None;
+        }
+        Some(
+            (offset..end)
+                .map(|byte| words[byte as usize / 2].to_le_bytes()[byte as usize % 2])
+                .collect(),
+        )
+    }
+
+    // Only RAM can be written, a byte at a time into its word
+    fn write_memory(&mut self, addr: u32, bytes: &[u8]) -> bool {
+        if addr as usize + bytes.len() > MEMORY_SIZE * 2 {
+            return false;
+        }
+        for (i, byte) in bytes.iter().enumerate() {
+            let at = addr as usize + i;
+            let mut word = self.cpu.read((at / 2) as u16).to_le_bytes();
+            word[at % 2] = *byte;
+            self.cpu.write((at / 2) as u16, u16::from_le_bytes(word));
+        }
+        true
+    }
+
+    // The next packet's data, skipping acks and stray bytes, None once the
+    // connection closes. Bad checksums are nacked for gdb to resend.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let Some(byte) = self.read_byte()? else {
+                return Ok(None);
+            };
+            if byte != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                match self.read_byte()? {
+                    Some(byte) => *digit = byte,
+                    None => return Ok(None),
+                }
+            }
+            let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            let expected = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok());
+            if self.ack {
+                let ok = expected == Some(sum);
+                self.conn.write_all(if ok { b"+" } else { b"-" })?;
+                self.conn.flush()?;
+                if !ok {
+                    continue;
+                }
+            }
+            return Ok(Some(String::from_utf8_lossy(&unescape(&data)).to_string()));
+        }
+    }
+
+    // Sends a packet, in ack mode until gdb acks it
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::new();
+        for byte in data.bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                escaped.extend([b'}', byte ^ 0x20]);
+            } else {
+                escaped.push(byte);
+            }
+        }
+        let sum = escaped
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        let mut packet = vec![b'$'];
+        packet.extend(&escaped);
+        packet.extend(format!("#{:02x}", sum).bytes());
+        loop {
+            self.conn.write_all(&packet)?;
+            self.conn.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            // gdb acks every packet it gets
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.conn.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+}
+
+// gdb's byte address of a ROM word
+fn rom_byte(addr: u16) -> u32 {
+    ROM_BASE + 2 * addr as u32
+}
+
+// The ROM word at a byte address, None outside ROM or between words
+fn rom_word(byte: u32) -> Option<u16> {
+    let offset = byte.checked_sub(ROM_BASE)?;
+    if offset % 2 != 0 {
+        return None;
+    }
+    u16::try_from(offset / 2).ok()
+}
+
+// Little endian bytes, at most 4, as a number
+fn le_value(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+// The `offset,length` piece of an XML document a qXfer read asks for
+fn xfer(document: &str, range: &str) -> String {
+    let Some((offset, len)) = range.split_once(',').and_then(|(offset, len)| {
+        Some((
+            usize::from_str_radix(offset, 16).ok()?,
+            usize::from_str_radix(len, 16).ok()?,
+        ))
+    }) else {
+        return "E01".to_string();
+    };
+    let bytes = document.as_bytes();
+    let chunk = &bytes[offset.min(bytes.len())..offset.saturating_add(len).min(bytes.len())];
+    let more = offset.saturating_add(len) < bytes.len();
+    format!(
+        "{}{}",
+        if more { 'm' } else { 'l' },
+        String::from_utf8_lossy(chunk)
+    )
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(text.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+// `}` escapes the next byte xored with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for byte in data {
+        if escaped {
+            bytes.push(byte ^ 0x20);
+            escaped = false;
+        } else if *byte == b'}' {
+            escaped = true;
+        } else {
+            bytes.push(*byte);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::thread;
+
+    use assembler::assemble;
+
+    // Plays gdb's side of the protocol over a real socket
+    struct Peer(TcpStream);
+
+    impl Peer {
+        fn send(&mut self, packet: &str) {
+            let sum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.0, "${}#{:02x}", packet, sum).unwrap();
+            assert_eq!(self.byte(), b'+', "{} wasn't acked", packet);
+        }
+
+        // Sends a packet and acks the reply
+        fn ask(&mut self, packet: &str) -> String {
+            self.send(packet);
+            assert_eq!(self.byte(), b'$');
+            let mut reply = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    byte => reply.push(byte),
+                }
+            }
+            self.byte();
+            self.byte();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(unescape(&reply)).unwrap()
+        }
+
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0];
+            self.0.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+    }
+
+    #[test]
+    fn a_scripted_session() {
+        let program = assemble("@2\nD=A\n@3\nD=D+A\n@R0\nM=D\n(END)\n@END\n0;JMP\n").unwrap();
+        let loaded = Loaded {
+            words: program.words.clone(),
+            symbols: SymbolMap::from_program(&program),
+            source: Vec::new(),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            GdbStub::new(stream, loaded, false).serve().unwrap();
+        });
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut gdb = Peer(stream);
+
+        assert_eq!(gdb.ask("?"), "S05");
+        // a, d, then pc as the byte address of ROM 0
+        assert_eq!(gdb.ask("g"), "0000_0000_00000100".replace('_', ""));
+        // @2 and D=A, low byte first
+        assert_eq!(gdb.ask("m10000,4"), "020010ec");
+        // on M=D at ROM 5
+        assert_eq!(gdb.ask("Z0,1000a,2"), "OK");
+        assert_eq!(gdb.ask("s"), "S05");
+        assert_eq!(gdb.ask("g"), "0200_0000_02000100".replace('_', ""));
+        assert_eq!(gdb.ask("c"), "S05");
+        assert_eq!(gdb.ask("p2"), "0a000100");
+        assert_eq!(gdb.ask("m0,2"), "0000");
+        assert_eq!(gdb.ask("z0,1000a,2"), "OK");
+        assert_eq!(gdb.ask("c"), "W00");
+        assert_eq!(gdb.ask("m0,2"), "0500");
+
+        assert_eq!(gdb.ask("qSymbol::"), "OK");
+        assert_eq!(gdb.ask("qSymbol::454e44"), "qSymbol:1000c:454e44");
+        assert_eq!(gdb.ask("qSymbol::5230"), "qSymbol:0:5230");
+        assert_eq!(gdb.ask("qSymbol::4e4f4e45"), "OK");
+        // between two ROM words
+        assert_eq!(gdb.ask("Z0,10001,2"), "E01");
+
+        gdb.send("k");
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use crate::cpu::Cpu;
+use crate::cpu::Stop;
+use crate::script::ScriptError;
+
+// Hack codes for the keys that aren't characters, F1-F12 follow at 141
+const KEY_NAMES: [(&str, u16); 14] = [
+    ("space", 32),
+    ("newline", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    Press(u16),
+    // without a key whatever is held is let go, with one only that key is
+    Release(Option<u16>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    // applied once this many cycles have run
+    pub cycle: u64,
+    pub action: KeyAction,
+}
+
+// Replays key events into the KBD register as the CPU reaches their cycles.
+// KBD only holds one code, so a release of a key that has since been
+// overtaken by another press leaves the newer key down. Every change to KBD
+// is recorded so the run's input can be saved and replayed exactly.
+#[derive(Debug, Default)]
+pub struct Keyboard {
+    events: Vec<KeyEvent>,
+    next: usize,
+    // cycle and new KBD value of each change
+    recorded: Vec<(u64, u16)>,
+}
+
+impl Keyboard {
+    pub fn new(events: Vec<KeyEvent>) -> Self {
+        Self {
+            events,
+            next: 0,
+            recorded: Vec::new(),
+        }
+    }
+
+    // Carries on the recording of a run restored from a snapshot
+    pub fn resume(&mut self, recorded: Vec<(u64, u16)>) {
+        self.recorded = recorded;
+    }
+
+    pub fn recorded(&self) -> &[(u64, u16)] {
+        &self.recorded
+    }
+
+    // Runs until the CPU has done `until` cycles in all or halts, pressing
+    // and releasing keys on the way
+    pub fn run(&mut self, cpu: &mut Cpu, until: u64) -> Stop {
+        self.run_with(cpu, until, Cpu::run)
+    }
+
+    // Like run with another way of running the CPU for a number of cycles,
+    // such as Engine::run
+    pub fn run_with(
+        &mut self,
+        cpu: &mut Cpu,
+        until: u64,
+        mut run: impl FnMut(&mut Cpu, u64) -> Stop,
+    ) -> Stop {
+        loop {
+            let next = self.apply(cpu);
+            let stop_at = next.map_or(until, |cycle| cycle.min(until));
+            let stop = run(cpu, stop_at.saturating_sub(cpu.cycles()));
+            if stop == Stop::Halted || cpu.cycles() >= until {
+                return stop;
+            }
+        }
+    }
+
+    // Applies every event that is due and returns the cycle of the next one.
+    // Events from before the current cycle are skipped, in a run restored
+    // from a snapshot they already happened.
+    pub fn apply(&mut self, cpu: &mut Cpu) -> Option<u64> {
+        while let Some(event) = self.events.get(self.next) {
+            if event.cycle > cpu.cycles() {
+                return Some(event.cycle);
+            }
+            self.next += 1;
+            if event.cycle < cpu.cycles() {
+                continue;
+            }
+            let key = match event.action {
+                KeyAction::Press(key) => key,
+                KeyAction::Release(None) => 0,
+                KeyAction::Release(Some(key)) if cpu.key() == key => 0,
+                KeyAction::Release(Some(_)) => cpu.key(),
+            };
+            if key != cpu.key() {
+                cpu.set_key(key);
+                self.recorded.push((event.cycle, key));
+            }
+        }
+        None
+    }
+}
+
+// One event a line, `CYCLE press KEY` or `CYCLE release [KEY]`, with //
+// comments. Cycles can't go backwards. KEY is, checked in this order:
+// - a single character, so q is 113 and 5 is the digit 53
+// - a name like left, space or f1
+// - `code:N` for any raw Hack code N, like code:130
+pub fn parse_keys(path: &str, text: &str) -> Result<Vec<KeyEvent>, ScriptError> {
+    let mut events: Vec<KeyEvent> = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let error = |message: String| ScriptError {
+            path: path.to_string(),
+            line: idx + 1,
+            message,
+        };
+        let code = match line.find("//") {
+            Some(at) => &line[..at],
+            None => line,
+        };
+        let fields: Vec<&str> = code.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+
+        let cycle = fields[0]
+            .parse::<u64>()
+            .map_err(|_| error(format!("expected a cycle count, found `{}`", fields[0])))?;
+        let key =
+            |text: &str| parse_key(text).ok_or_else(|| error(format!("unknown key `{}`", text)));
+        let action = match fields[1..] {
+            ["press", name] => KeyAction::Press(key(name)?),
+            ["release"] => KeyAction::Release(None),
+            ["release", name] => KeyAction::Release(Some(key(name)?)),
+            _ => {
+                return Err(error(
+                    "expected `CYCLE press KEY` or `CYCLE release [KEY]`".to_string(),
+                ));
+            }
+        };
+        if let Some(last) = events.last()
+            && cycle < last.cycle
+        {
+            return Err(error(format!(
+                "cycle {} comes before the previous event at {}",
+                cycle, last.cycle
+            )));
+        }
+        events.push(KeyEvent { cycle, action });
+    }
+    Ok(events)
+}
+
+// The recorded changes as a script parse_keys reads back, with `code:N`
+// rather than names so nothing is lost
+pub fn keys_script(recorded: &[(u64, u16)]) -> String {
+    let mut text = String::new();
+    for (cycle, key) in recorded {
+        if *key == 0 {
+            text.push_str(&format!("{} release\n", cycle));
+        } else {
+            text.push_str(&format!("{} press code:{}\n", cycle, key));
+        }
+    }
+    text
+}
+
+pub fn parse_key(text: &str) -> Option<u16> {
+    let mut chars = text.chars();
+    if let (Some(ch), None) = (chars.next(), chars.next())
+        && ch.is_ascii_graphic()
+    {
+        return Some(ch as u16);
+    }
+    if let Some(code) = text.strip_prefix("code:") {
+        return code.parse::<u16>().ok();
+    }
+    let name = text.to_ascii_lowercase();
+    if let Some((_, code)) = KEY_NAMES.iter().find(|(key, _)| *key == name) {
+        return Some(*code);
+    }
+    name.strip_prefix('f')
+        .and_then(|n| n.parse::<u16>().ok())
+        .filter(|n| (1..=12).contains(n))
+        .map(|n| 140 + n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digits_are_characters_and_codes_need_a_prefix() {
+        assert_eq!(parse_key("5"), Some(53));
+        assert_eq!(parse_key("q"), Some(113));
+        assert_eq!(parse_key("code:5"), Some(5));
+        assert_eq!(parse_key("code:130"), Some(130));
+        assert_eq!(parse_key("130"), None);
+        assert_eq!(parse_key("code:x"), None);
+    }
+
+    #[test]
+    fn names_and_function_keys() {
+        assert_eq!(parse_key("left"), Some(130));
+        assert_eq!(parse_key("SPACE"), Some(32));
+        assert_eq!(parse_key("f1"), Some(141));
+        assert_eq!(parse_key("f12"), Some(152));
+        assert_eq!(parse_key("f13"), None);
+    }
+
+    #[test]
+    fn recorded_keys_read_back_the_same() {
+        let recorded = [(10, 53), (20, 0), (30, 130), (40, 131), (50, 0)];
+        let events = parse_keys("test", &keys_script(&recorded)).unwrap();
+        let mut cpu = Cpu::new(vec![0; 100]);
+        let mut keyboard = Keyboard::new(events);
+        keyboard.run(&mut cpu, 60);
+        assert_eq!(keyboard.recorded(), &recorded);
+    }
+}
@@ -0,0 +1,39 @@
+use assembler::init_symbol_table;
+
+pub mod cpu;
+
+pub mod debugger;
+
+pub mod engine;
+
+pub mod gdb;
+
+pub mod keyboard;
+
+pub mod loader;
+
+pub mod profile;
+
+pub mod screen;
+
+pub mod script;
+
+pub mod snapshot;
+
+pub mod trace;
+
+// RAM address from a number or a predefined symbol like R2, SP or SCREEN
+pub fn parse_address(text: &str) -> Option<u16> {
+    if let Some(addr) = init_symbol_table().get(text) {
+        return Some(*addr);
+    }
+    text.parse::<u16>().ok().filter(|addr| *addr < 0x8000)
+}
+
+// Decimal word, negative values are stored as 16 bit two's complement
+pub fn parse_value(text: &str) -> Option<u16> {
+    text.parse::<i16>()
+        .map(|v| v as u16)
+        .or_else(|_| text.parse::<u16>())
+        .ok()
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use assembler::AsmOptions;
+use assembler::Program;
+use assembler::ROM_SIZE;
+use assembler::SourceFile;
+use assembler::assemble_sources;
+use assembler::disassembler::read_hack;
+use assembler::error::AsmError;
+use assembler::symbols::SymbolMap;
+
+#[derive(Debug)]
+pub enum LoadError {
+    // path that couldn't be read
+    Io(String, io::Error),
+    // bad .hack text or .asm source
+    Asm(AsmError),
+    // word count of a program that doesn't fit
+    TooLarge(usize),
+    // byte count of a binary image with half a word at the end
+    OddLength(usize),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(path, e) => writeln!(f, "error: can't read {}: {}", path, e),
+            LoadError::Asm(e) => write!(f, "{}", e),
+            LoadError::TooLarge(words) => writeln!(
+                f,
+                "error: program has {} words but the ROM only holds {}",
+                words, ROM_SIZE
+            ),
+            LoadError::OddLength(bytes) => {
+                writeln!(f, "error: {} bytes isn't a whole number of words", bytes)
+            }
+        }
+    }
+}
+
+// A program with what the debugger can know about it
+#[derive(Debug)]
+pub struct Loaded {
+    pub words: Vec<u16>,
+    // from the assembly or a .sym file beside the binary, else just the
+    // predefined names
+    pub symbols: SymbolMap,
+    // `file:line  text` for each word, empty unless loaded from .asm
+    pub source: Vec<String>,
+}
+
+// Like load_rom but keeps the symbols and source lines. For a .hack or .bin
+// file the symbols are read from the .sym file next to it if there is one.
+pub fn load_program(path: &Path) -> Result<Loaded, LoadError> {
+    let name = path.to_string_lossy();
+    if name.ends_with(".asm") {
+        let program = assemble_file(path)?;
+        let source = program
+            .source_lines
+            .iter()
+            .map(|line| {
+                let file = Path::new(&program.files[line.file]).file_name();
+                format!(
+                    "{}:{}  {}",
+                    file.unwrap_or_default().to_string_lossy(),
+                    line.line_num,
+                    line.text.trim()
+                )
+            })
+            .collect();
+        return Ok(Loaded {
+            symbols: SymbolMap::from_program(&program),
+            words: program.words,
+            source,
+        });
+    }
+
+    let words = load_rom(path)?;
+    let stem = name
+        .trim_end_matches(".le.bin")
+        .trim_end_matches(".bin")
+        .trim_end_matches(".hack");
+    let sym_path = PathBuf::from(format!("{}.sym", stem));
+    let symbols = match fs::read_to_string(&sym_path) {
+        Ok(contents) => {
+            let mut symbols = SymbolMap::parse_text(&contents).map_err(|mut errors| {
+                errors.path = sym_path.to_string_lossy().to_string();
+                LoadError::Asm(errors)
+            })?;
+            symbols.predefined = SymbolMap::predefined().predefined;
+            symbols
+        }
+        Err(_) => SymbolMap::predefined(),
+    };
+    Ok(Loaded {
+        words,
+        symbols,
+        source: Vec::new(),
+    })
+}
+
+// Picks the format from the extension: .hack text, .bin big-endian or
+// .le.bin little-endian images as written by the assembler, or .asm
+// which is assembled first
+pub fn load_rom(path: &Path) -> Result<Vec<u16>, LoadError> {
+    let name = path.to_string_lossy();
+    let io_error = |e| LoadError::Io(name.to_string(), e);
+    let words = if name.ends_with(".bin") {
+        let bytes = fs::read(path).map_err(io_error)?;
+        parse_binary(&bytes, name.ends_with(".le.bin"))?
+    } else if name.ends_with(".asm") {
+        assemble_file(path)?.words
+    } else {
+        let contents = fs::read_to_string(path).map_err(io_error)?;
+        read_hack(&contents).map_err(|mut errors| {
+            errors.path = name.to_string();
+            LoadError::Asm(errors)
+        })?
+    };
+
+    if words.len() > ROM_SIZE {
+        return Err(LoadError::TooLarge(words.len()));
+    }
+    Ok(words)
+}
+
+fn assemble_file(path: &Path) -> Result<Program, LoadError> {
+    let name = path.to_string_lossy();
+    let source = SourceFile {
+        path: name.to_string(),
+        contents: fs::read_to_string(path).map_err(|e| LoadError::Io(name.to_string(), e))?,
+    };
+    let program = assemble_sources(&[source], AsmOptions::default()).map_err(LoadError::Asm)?;
+    if program.words.len() > ROM_SIZE {
+        return Err(LoadError::TooLarge(program.words.len()));
+    }
+    Ok(program)
+}
+
+pub fn parse_binary(bytes: &[u8], little_endian: bool) -> Result<Vec<u16>, LoadError> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(LoadError::OddLength(bytes.len()));
+    }
+    let words = bytes
+        .chunks(2)
+        .map(|pair| {
+            let pair = [pair[0], pair[1]];
+            if little_endian {
+                u16::from_le_bytes(pair)
+            } else {
+                u16::from_be_bytes(pair)
+            }
+        })
+        .collect();
+    Ok(words)
+}
@@ -0,0 +1,539 @@
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::net::TcpListener;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::time::Instant;
+
+use emulator::cpu::Cpu;
+use emulator::cpu::Stop;
+use emulator::debugger::Debugger;
+use emulator::engine::Engine;
+use emulator::gdb::GdbStub;
+use emulator::gdb::Stdio;
+use emulator::keyboard::Keyboard;
+use emulator::keyboard::keys_script;
+use emulator::keyboard::parse_keys;
+use emulator::loader::load_program;
+use emulator::parse_address;
+use emulator::parse_value;
+use emulator::profile::Profiler;
+use emulator::screen::ImageFormat;
+use emulator::screen::ascii;
+use emulator::script::run_script;
+use emulator::snapshot::Snapshot;
+use emulator::snapshot::diff;
+use emulator::trace::Tracer;
+use emulator::trace::parse_range;
+
+// enough for any program that halts on its own, Pong never does
+const DEFAULT_CYCLES: u64 = 10_000_000;
+
+const USAGE: &str = "\
+usage: emulator <file.hack|file.bin|file.asm> [--cycles N] [--set ADDR=VALUE]...
+           [--ram FROM-TO] [--extended] [--screen [CYCLE:]FILE]... [--ascii]
+           [--keys FILE] [--trace FILE [--trace-only RANGE]... [--trace-last N]]
+           [--profile] [--folded FILE] [--restore SNAPSHOT]
+           [--snapshot [CYCLE:]FILE]... [--record FILE]
+       emulator test <file.tst>...
+       emulator debug <file> [--extended]
+       emulator bench <file> [--cycles N] [--extended]
+       emulator gdb <file> [--port N | --stdio] [--extended]
+       emulator snapshot info FILE | diff OLD NEW [--all] | keys FILE
+";
+
+// emulator <file.hack|file.bin|file.asm> [--cycles N] [--set ADDR=VALUE]...
+//     [--ram FROM-TO] [--extended] [--screen [CYCLE:]FILE]... [--ascii]
+//     [--keys FILE] [--trace FILE [--trace-only RANGE]... [--trace-last N]]
+//     [--profile] [--folded FILE] [--restore SNAPSHOT]
+//     [--snapshot [CYCLE:]FILE]... [--record FILE]
+// Runs headless until the program reaches its (END) loop, then prints the
+// registers and a range of RAM. Exits with 2 if the cycle limit ran out.
+// Runs that aren't traced or profiled go a basic block at a time, see Engine.
+// --screen saves the screen as .pbm, .png or .txt when the run stops, or
+// once CYCLE cycles have run, and --ascii prints it to the terminal.
+// --keys replays a keyboard script, see keyboard::parse_keys.
+// --trace writes every instruction run, or those in the ROM ranges given to
+// --trace-only, or only the last N of them. A traced run also stops, with
+// exit code 3, on an illegal state, see Tracer::run.
+// --profile prints the cycles spent in each function of a program the VM
+// translator wrote and --folded saves its call stacks for a flamegraph.
+// --snapshot saves the whole machine state like --screen saves the screen,
+// --restore carries on from one, with --cycles still counting from the
+// start of the first run, and --record saves the keyboard input as a
+// --keys script that replays the run exactly.
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 {
+        eprint!("{}", USAGE);
+        process::exit(1);
+    }
+    if args.get(1).is_some_and(|arg| arg == "test") {
+        run_tests(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "debug") {
+        run_debugger(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "bench") {
+        run_bench(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "gdb") {
+        run_gdb(&args[2..]);
+        return;
+    }
+    if args.get(1).is_some_and(|arg| arg == "snapshot") {
+        run_snapshot(&args[2..]);
+        return;
+    }
+    let file_path = &args[1];
+    let flag = |name: &str| {
+        args.iter()
+            .position(|arg| arg == name)
+            .map(|at| args.get(at + 1).map(|v| v.as_str()).unwrap_or(""))
+    };
+
+    let max_cycles = match flag("--cycles") {
+        Some(n) => n
+            .parse::<u64>()
+            .unwrap_or_else(|_| fail("--cycles expects a number")),
+        None => DEFAULT_CYCLES,
+    };
+    let (from, to) = match flag("--ram") {
+        Some(range) => range
+            .split_once('-')
+            .and_then(|(from, to)| Some((parse_address(from)?, parse_address(to)?)))
+            .unwrap_or_else(|| fail("--ram expects FROM-TO, e.g. 0-15")),
+        None => (0, 15),
+    };
+
+    let program = load_program(Path::new(file_path)).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(1);
+    });
+    let labels = program.symbols.labels;
+    let mut cpu = Cpu::new(program.words);
+    cpu.set_extended(args.iter().any(|arg| arg == "--extended"));
+    let mut history = Vec::new();
+    if let Some(path) = flag("--restore") {
+        let restored = Snapshot::load(Path::new(path))
+            .and_then(|snapshot| snapshot.restore(&mut cpu).map(|_| snapshot))
+            .unwrap_or_else(|e| {
+                eprint!("{}", e);
+                process::exit(1);
+            });
+        history = restored.keys;
+    }
+    for pair in args.windows(2).filter(|pair| pair[0] == "--set") {
+        let (addr, value) = pair[1]
+            .split_once('=')
+            .and_then(|(addr, value)| Some((parse_address(addr)?, parse_value(value)?)))
+            .unwrap_or_else(|| fail("--set expects ADDR=VALUE, e.g. R0=3"));
+        cpu.write(addr, value);
+    }
+    let mut captures: Vec<(Option<u64>, Capture)> = args
+        .windows(2)
+        .filter_map(|pair| match pair[0].as_str() {
+            "--screen" => {
+                let (cycle, path) = parse_capture(&pair[1], "--screen", "pong.png");
+                if ImageFormat::from_path(&path).is_none() {
+                    fail("--screen can write .pbm, .png or .txt files");
+                }
+                Some((cycle, Capture::Screen(path)))
+            }
+            "--snapshot" => {
+                let (cycle, path) = parse_capture(&pair[1], "--snapshot", "pong.snap");
+                Some((cycle, Capture::Snapshot(path)))
+            }
+            _ => None,
+        })
+        .collect();
+    captures.sort_by_key(|(cycle, _)| *cycle);
+    if captures
+        .iter()
+        .any(|(cycle, _)| cycle.is_some_and(|c| c > max_cycles || c < cpu.cycles()))
+    {
+        fail("--screen or --snapshot cycle is outside the run");
+    }
+    let mut keyboard = match flag("--keys") {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .unwrap_or_else(|e| fail(&format!("can't read {}: {}", path, e)));
+            let events = parse_keys(path, &text).unwrap_or_else(|e| {
+                eprint!("{}", e);
+                process::exit(1);
+            });
+            Keyboard::new(events)
+        }
+        None => Keyboard::default(),
+    };
+    keyboard.resume(history);
+    let mut tracer = flag("--trace").map(|path| {
+        let ranges = args
+            .windows(2)
+            .filter(|pair| pair[0] == "--trace-only")
+            .map(|pair| {
+                parse_range(&pair[1], &labels, cpu.rom().len()).unwrap_or_else(|| {
+                    fail(&format!(
+                        "--trace-only expects FROM-TO, an address or a label, found `{}`",
+                        pair[1]
+                    ))
+                })
+            })
+            .collect();
+        let last = flag("--trace-last").map(|n| {
+            n.parse::<usize>()
+                .unwrap_or_else(|_| fail("--trace-last expects a number"))
+        });
+        let file =
+            File::create(path).unwrap_or_else(|e| fail(&format!("can't write {}: {}", path, e)));
+        Tracer::new(BufWriter::new(file), ranges, last)
+    });
+    let folded = flag("--folded");
+    let mut profiler = (args.iter().any(|arg| arg == "--profile") || folded.is_some())
+        .then(|| Profiler::new(&labels, cpu.rom()));
+    if tracer.is_some() && profiler.is_some() {
+        fail("--trace can't be used with --profile or --folded");
+    }
+    if let Some(profiler) = &profiler
+        && !profiler.has_calls()
+    {
+        eprintln!("warning: no VM calls found, everything is counted as (top)");
+    }
+    let mut engine = Engine::new(cpu.rom(), cpu.extended());
+    let mut advance =
+        |cpu: &mut Cpu, keyboard: &mut Keyboard, until: u64| match (&mut tracer, &mut profiler) {
+            (Some(tracer), _) => tracer
+                .run(cpu, keyboard, until)
+                .unwrap_or_else(|e| fail(&format!("can't write the trace: {}", e))),
+            (None, Some(profiler)) => profiler.run(cpu, keyboard, until),
+            (None, None) => keyboard.run_with(cpu, until, |cpu, n| engine.run(cpu, n)),
+        };
+
+    // run to each capture in turn, a halted program's state can't change
+    let mut faulted = false;
+    for (cycle, capture) in &captures {
+        if let Some(cycle) = cycle
+            && !faulted
+        {
+            faulted = advance(&mut cpu, &mut keyboard, *cycle) == Stop::Fault;
+            save_capture(&cpu, &keyboard, capture);
+        }
+    }
+    let stop = if faulted {
+        Stop::Fault
+    } else {
+        advance(&mut cpu, &mut keyboard, max_cycles)
+    };
+    for (_, capture) in captures.iter().filter(|(cycle, _)| cycle.is_none()) {
+        save_capture(&cpu, &keyboard, capture);
+    }
+    if let Some(path) = flag("--record")
+        && let Err(e) = fs::write(path, keys_script(keyboard.recorded()))
+    {
+        fail(&format!("can't write {}: {}", path, e));
+    }
+    if let Some(tracer) = &mut tracer
+        && let Err(e) = tracer.finish(stop, &cpu)
+    {
+        fail(&format!("can't write the trace: {}", e));
+    }
+    match stop {
+        Stop::Halted => println!(
+            "{}: halted at PC {} after {} cycles",
+            file_path,
+            cpu.pc(),
+            cpu.cycles()
+        ),
+        Stop::CycleLimit => println!(
+            "{}: still running at PC {} after {} cycles",
+            file_path,
+            cpu.pc(),
+            cpu.cycles()
+        ),
+        Stop::Fault => println!(
+            "{}: stopped at PC {} after {} cycles, {}",
+            file_path,
+            cpu.pc(),
+            cpu.cycles(),
+            tracer
+                .and_then(|t| t.fault)
+                .or_else(|| profiler.as_ref().and_then(|p| p.fault.clone()))
+                .unwrap_or_default()
+        ),
+    }
+    println!("A: {}  D: {}  PC: {}", cpu.a(), cpu.d() as i16, cpu.pc());
+    for addr in from..=to {
+        println!("RAM[{}]: {}", addr, cpu.read(addr) as i16);
+    }
+    if args.iter().any(|arg| arg == "--ascii") {
+        print!("{}", ascii(cpu.screen(), 4, 8));
+    }
+    if let Some(profiler) = &mut profiler {
+        profiler.finish(cpu.cycles());
+        if args.iter().any(|arg| arg == "--profile") {
+            print!("{}", profiler.report());
+        }
+        if let Some(path) = folded
+            && let Err(e) = fs::write(path, profiler.folded())
+        {
+            fail(&format!("can't write {}: {}", path, e));
+        }
+    }
+
+    match stop {
+        Stop::Halted => {}
+        Stop::CycleLimit => process::exit(2),
+        Stop::Fault => process::exit(3),
+    }
+}
+
+enum Capture {
+    Screen(PathBuf),
+    Snapshot(PathBuf),
+}
+
+// `[CYCLE:]FILE`, without a cycle the file is saved when the run stops
+fn parse_capture(text: &str, flag: &str, example: &str) -> (Option<u64>, PathBuf) {
+    match text.split_once(':') {
+        Some((cycle, file)) => {
+            let cycle = cycle.parse::<u64>().unwrap_or_else(|_| {
+                fail(&format!(
+                    "{} expects [CYCLE:]FILE, e.g. 5000:{}",
+                    flag, example
+                ))
+            });
+            (Some(cycle), PathBuf::from(file))
+        }
+        None => (None, PathBuf::from(text)),
+    }
+}
+
+fn save_capture(cpu: &Cpu, keyboard: &Keyboard, capture: &Capture) {
+    match capture {
+        Capture::Screen(path) => {
+            let Some(format) = ImageFormat::from_path(path) else {
+                return;
+            };
+            if let Err(e) = fs::write(path, format.encode(cpu.screen())) {
+                fail(&format!("can't write {}: {}", path.to_string_lossy(), e));
+            }
+        }
+        Capture::Snapshot(path) => {
+            if let Err(e) = Snapshot::take(cpu, keyboard.recorded()).save(path) {
+                eprint!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+// bench <file.hack|file.bin|file.asm> [--cycles N] [--extended], runs the
+// program for N cycles, 10M by default, a word at a time and then a block at
+// a time, and exits with 1 if they end up in different states
+fn run_bench(args: &[String]) {
+    let file_path = args
+        .first()
+        .unwrap_or_else(|| fail("bench expects a .hack, .bin or .asm file"));
+    let cycles = match args.iter().position(|arg| arg == "--cycles") {
+        Some(at) => args
+            .get(at + 1)
+            .and_then(|n| n.parse::<u64>().ok())
+            .unwrap_or_else(|| fail("--cycles expects a number")),
+        None => DEFAULT_CYCLES,
+    };
+    let program = load_program(Path::new(file_path)).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(1);
+    });
+    let mut naive = Cpu::new(program.words);
+    naive.set_extended(args.iter().any(|arg| arg == "--extended"));
+    let mut fast = naive.clone();
+
+    let start = Instant::now();
+    naive.run(cycles);
+    let naive_time = start.elapsed();
+    let start = Instant::now();
+    let mut engine = Engine::new(fast.rom(), fast.extended());
+    engine.run(&mut fast, cycles);
+    let fast_time = start.elapsed();
+
+    let rate = |cycles: u64, secs: f64| cycles as f64 / secs.max(1e-9) / 1e6;
+    println!(
+        "word at a time:  {} cycles in {:.3}s, {:.1}M cycles/s",
+        naive.cycles(),
+        naive_time.as_secs_f64(),
+        rate(naive.cycles(), naive_time.as_secs_f64())
+    );
+    let (blocks, fused) = engine.stats();
+    println!(
+        "block at a time: {} cycles in {:.3}s, {:.1}M cycles/s, {} blocks, {} fused ops",
+        fast.cycles(),
+        fast_time.as_secs_f64(),
+        rate(fast.cycles(), fast_time.as_secs_f64()),
+        blocks,
+        fused
+    );
+    println!(
+        "speedup {:.2}x",
+        naive_time.as_secs_f64() / fast_time.as_secs_f64().max(1e-9)
+    );
+    let same = naive.cycles() == fast.cycles()
+        && naive.pc() == fast.pc()
+        && naive.a() == fast.a()
+        && naive.d() == fast.d()
+        && naive.ram() == fast.ram();
+    if !same {
+        println!("the runs ended in different states");
+        process::exit(1);
+    }
+}
+
+// gdb <file.hack|file.bin|file.asm> [--port N | --stdio] [--extended],
+// serves one gdb session on 127.0.0.1, port 1234 by default, or over stdin
+// and stdout for `target remote | emulator gdb FILE --stdio`
+fn run_gdb(args: &[String]) {
+    let file_path = args
+        .first()
+        .unwrap_or_else(|| fail("gdb expects a .hack, .bin or .asm file"));
+    let program = load_program(Path::new(file_path)).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(1);
+    });
+    let extended = args.iter().any(|arg| arg == "--extended");
+    let result = if args.iter().any(|arg| arg == "--stdio") {
+        GdbStub::new(Stdio, program, extended).serve()
+    } else {
+        let port = match args.iter().position(|arg| arg == "--port") {
+            Some(at) => args
+                .get(at + 1)
+                .and_then(|port| port.parse::<u16>().ok())
+                .unwrap_or_else(|| fail("--port expects a number")),
+            None => 1234,
+        };
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .unwrap_or_else(|e| fail(&format!("can't listen on port {}: {}", port, e)));
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        let (stream, _) = listener
+            .accept()
+            .unwrap_or_else(|e| fail(&format!("can't accept a connection: {}", e)));
+        GdbStub::new(stream, program, extended).serve()
+    };
+    if let Err(e) = result {
+        fail(&format!("gdb connection failed: {}", e));
+    }
+}
+
+// snapshot info FILE, snapshot diff OLD NEW [--all] or snapshot keys FILE.
+// diff lists the first 10 changed words of each region unless --all and
+// exits with 1 if the snapshots differ, keys prints the recorded input as a
+// --keys script.
+fn run_snapshot(args: &[String]) {
+    let load = |path: &String| {
+        Snapshot::load(Path::new(path)).unwrap_or_else(|e| {
+            eprint!("{}", e);
+            process::exit(1);
+        })
+    };
+    match args {
+        [command, path] if command == "info" => print!("{}", load(path).info()),
+        [command, path] if command == "keys" => print!("{}", keys_script(&load(path).keys)),
+        [command, old, new, rest @ ..] if command == "diff" => {
+            let limit = if rest.iter().any(|arg| arg == "--all") {
+                usize::MAX
+            } else {
+                10
+            };
+            let changes = diff(&load(old), &load(new), limit);
+            if changes.is_empty() {
+                println!("{} and {} are the same", old, new);
+                return;
+            }
+            print!("{}", changes);
+            process::exit(1);
+        }
+        _ => fail("snapshot expects info FILE, diff OLD NEW [--all] or keys FILE"),
+    }
+}
+
+// debug <file.hack|file.bin|file.asm> [--extended], commands come from stdin
+fn run_debugger(args: &[String]) {
+    let file_path = args
+        .first()
+        .unwrap_or_else(|| fail("debug expects a .hack, .bin or .asm file"));
+    let program = load_program(Path::new(file_path)).unwrap_or_else(|e| {
+        eprint!("{}", e);
+        process::exit(1);
+    });
+    let mut debugger = Debugger::new(program, args.iter().any(|arg| arg == "--extended"));
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    if let Err(e) = debugger.repl(stdin.lock(), &mut stdout) {
+        fail(&e.to_string());
+    }
+}
+
+// test <file.tst>..., runs each script and diffs its output with the .cmp
+// file, exits with 1 if any script failed
+fn run_tests(paths: &[String]) {
+    if paths.is_empty() {
+        fail("test expects .tst scripts to run");
+    }
+    let mut failed = false;
+    for path in paths {
+        let report = match run_script(Path::new(path)) {
+            Ok(report) => report,
+            Err(e) => {
+                eprint!("{}", e);
+                failed = true;
+                continue;
+            }
+        };
+        for echo in &report.echoes {
+            println!("{}: {}", path, echo);
+        }
+        if report.cmp_path.is_none() {
+            println!(
+                "{}: {} lines of output, nothing to compare",
+                path, report.lines
+            );
+            continue;
+        }
+        if report.mismatches.is_empty() {
+            println!("{}: passed, {} lines match", path, report.lines);
+            continue;
+        }
+
+        failed = true;
+        println!(
+            "{}: failed, {} line{} differ from {}",
+            path,
+            report.mismatches.len(),
+            if report.mismatches.len() == 1 {
+                ""
+            } else {
+                "s"
+            },
+            report.cmp_path.unwrap_or_default().to_string_lossy()
+        );
+        for mismatch in &report.mismatches {
+            println!("line {}:", mismatch.line);
+            println!("- {}", mismatch.expected.as_deref().unwrap_or("(missing)"));
+            println!("+ {}", mismatch.actual.as_deref().unwrap_or("(missing)"));
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use assembler::SymbolTable;
+
+use crate::cpu::Cpu;
+use crate::cpu::Stop;
+use crate::keyboard::Keyboard;
+
+// `0;JMP`, the last word of every call the VM translator writes
+const JUMP: u16 = 0b1110_1010_1000_0111;
+// `A=M`, a return ends with `@retAddr A=M 0;JMP`
+const A_FROM_M: u16 = 0b1111_1100_0010_0000;
+// what runs before the first call, the bootstrap or a test's setup
+const ROOT: &str = "(top)";
+
+#[derive(Debug, Default, Clone)]
+pub struct FunctionStats {
+    pub calls: u64,
+    // cycles spent in the function's own code
+    pub exclusive: u64,
+    // cycles from its calls being made to them returning, recursive calls
+    // aren't counted twice
+    pub inclusive: u64,
+}
+
+struct Frame {
+    function: String,
+    ret: Option<u16>,
+    start: u64,
+    // index into Profiler::stacks
+    node: usize,
+}
+
+// Attributes each cycle to the VM function it ran in. The translator ends a
+// call with `@callee 0;JMP (caller$retN)`, so the `0;JMP` just before a
+// `$ret` label is a call to wherever it lands, and a later `A=M 0;JMP` to
+// that label is the return. Functions are named by their `(Function.name)`
+// label.
+pub struct Profiler {
+    // return address of each call site by the address of its 0;JMP
+    calls: HashMap<u16, u16>,
+    names: HashMap<u16, String>,
+    frames: Vec<Frame>,
+    pub functions: HashMap<String, FunctionStats>,
+    // distinct call stacks as (parent, function) and the cycles spent with
+    // each on top
+    stacks: Vec<(Option<usize>, String, u64)>,
+    nodes: HashMap<(Option<usize>, String), usize>,
+    pub fault: Option<String>,
+}
+
+impl Profiler {
+    pub fn new(labels: &SymbolTable, rom: &[u16]) -> Self {
+        let mut calls = HashMap::new();
+        let mut names: HashMap<u16, String> = HashMap::new();
+        for (name, addr) in labels {
+            if let Some((_, n)) = name.rsplit_once("$ret")
+                && !n.is_empty()
+                && n.bytes().all(|b| b.is_ascii_digit())
+                && *addr > 0
+                && rom.get(*addr as usize - 1) == Some(&JUMP)
+            {
+                calls.insert(*addr - 1, *addr);
+            }
+            // several labels can share an address, prefer a function's own
+            let better = match names.get(addr) {
+                Some(current) => {
+                    (name.contains('$'), name.as_str()) < (current.contains('$'), current.as_str())
+                }
+                None => true,
+            };
+            if better {
+                names.insert(*addr, name.clone());
+            }
+        }
+
+        let mut profiler = Self {
+            calls,
+            names,
+            frames: Vec::new(),
+            functions: HashMap::new(),
+            stacks: Vec::new(),
+            nodes: HashMap::new(),
+            fault: None,
+        };
+        let node = profiler.node(None, ROOT);
+        profiler.frames.push(Frame {
+            function: ROOT.to_string(),
+            ret: None,
+            start: 0,
+            node,
+        });
+        profiler
+            .functions
+            .entry(ROOT.to_string())
+            .or_default()
+            .calls = 1;
+        profiler
+    }
+
+    // true if the program has calls the translator wrote, without them
+    // everything lands in (top)
+    pub fn has_calls(&self) -> bool {
+        !self.calls.is_empty()
+    }
+
+    fn node(&mut self, parent: Option<usize>, function: &str) -> usize {
+        let key = (parent, function.to_string());
+        if let Some(node) = self.nodes.get(&key) {
+            return *node;
+        }
+        self.stacks.push((parent, function.to_string(), 0));
+        self.nodes.insert(key, self.stacks.len() - 1);
+        self.stacks.len() - 1
+    }
+
+    // Keyboard::run a step at a time, following calls and returns. Like
+    // Tracer::run it stops with a fault once PC runs past the program.
+    pub fn run(&mut self, cpu: &mut Cpu, keyboard: &mut Keyboard, until: u64) -> Stop {
+        while cpu.cycles() < until {
+            keyboard.apply(cpu);
+            if cpu.is_halted() {
+                return Stop::Halted;
+            }
+            if cpu.pc() as usize >= cpu.rom().len() {
+                self.fault = Some(format!("PC {} is past the end of the program", cpu.pc()));
+                return Stop::Fault;
+            }
+            let step = cpu.step();
+            let indirect = step.pc > 0 && cpu.rom().get(step.pc as usize - 1) == Some(&A_FROM_M);
+            self.count(step.pc, cpu.pc(), indirect, cpu.cycles());
+        }
+        if cpu.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::CycleLimit
+        }
+    }
+
+    // One cycle at `pc` that left the CPU at `next`, `now` cycles in.
+    // `indirect` if the word before `pc` was A=M, only such a jump returns.
+    fn count(&mut self, pc: u16, next: u16, indirect: bool, now: u64) {
+        let top = self.frames.last().expect("the root frame is never popped");
+        let node = top.node;
+        self.stacks[node].2 += 1;
+        self.functions
+            .get_mut(&top.function)
+            .expect("every frame's function has stats")
+            .exclusive += 1;
+        // checked before the fall through, the bootstrap's call lands on
+        // Sys.init right after it
+        if let Some(ret) = self.calls.get(&pc).copied() {
+            let function = match self.names.get(&next) {
+                Some(name) => name.clone(),
+                None => format!("@{}", next),
+            };
+            let node = self.node(Some(node), &function);
+            self.functions.entry(function.clone()).or_default().calls += 1;
+            self.frames.push(Frame {
+                function,
+                ret: Some(ret),
+                start: now,
+                node,
+            });
+            return;
+        }
+        if next == pc.wrapping_add(1) {
+            return;
+        }
+        if indirect && let Some(depth) = self.frames.iter().rposition(|f| f.ret == Some(next)) {
+            while self.frames.len() > depth {
+                self.pop(now);
+            }
+        }
+    }
+
+    fn pop(&mut self, now: u64) {
+        let Some(frame) = self.frames.pop() else {
+            return;
+        };
+        if self.frames.iter().all(|f| f.function != frame.function) {
+            self.functions
+                .get_mut(&frame.function)
+                .expect("every frame's function has stats")
+                .inclusive += now - frame.start;
+        }
+    }
+
+    // Closes the frames still open when the run stopped, the root included
+    pub fn finish(&mut self, now: u64) {
+        while !self.frames.is_empty() {
+            self.pop(now);
+        }
+    }
+
+    // One line per function, most exclusive cycles first
+    pub fn report(&self) -> String {
+        let total = self.stacks.iter().map(|(_, _, cycles)| cycles).sum::<u64>();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.max(1) as f64;
+        let mut functions: Vec<(&String, &FunctionStats)> = self.functions.iter().collect();
+        functions.sort_by(|(a_name, a), (b_name, b)| {
+            b.exclusive
+                .cmp(&a.exclusive)
+                .then(b.inclusive.cmp(&a.inclusive))
+                .then(a_name.cmp(b_name))
+        });
+        let width = functions
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or(0)
+            .max("function".len());
+
+        let mut text = format!(
+            "{:<width$}  {:>8}  {:>12}  {:>6}  {:>12}  {:>6}\n",
+            "function", "calls", "exclusive", "%", "inclusive", "%"
+        );
+        for (name, stats) in functions {
+            text.push_str(&format!(
+                "{:<width$}  {:>8}  {:>12}  {:>6.2}  {:>12}  {:>6.2}\n",
+                name,
+                stats.calls,
+                stats.exclusive,
+                percent(stats.exclusive),
+                stats.inclusive,
+                percent(stats.inclusive)
+            ));
+        }
+        text.push_str(&format!("{} cycles\n", total));
+        text
+    }
+
+    // `caller;callee;... cycles` for each call stack, the input that
+    // flamegraph.pl and similar tools take
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for (node, (_, _, cycles)) in self.stacks.iter().enumerate() {
+            if *cycles == 0 {
+                continue;
+            }
+            let mut path = Vec::new();
+            let mut at = Some(node);
+            while let Some(node) = at {
+                path.push(self.stacks[node].1.as_str());
+                at = self.stacks[node].0;
+            }
+            path.reverse();
+            lines.push(format!("{} {}", path.join(";"), cycles));
+        }
+        lines.sort();
+        let mut text = lines.join("\n");
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler::assemble;
+
+    #[test]
+    fn running_past_the_end_of_the_program_is_a_fault() {
+        let program = assemble("@2\nD=A\n@3\nD=D+A\n@0\nM=D\n").unwrap();
+        let mut cpu = Cpu::new(program.words);
+        let mut profiler = Profiler::new(&program.labels, cpu.rom());
+        let stop = profiler.run(&mut cpu, &mut Keyboard::default(), 1000);
+        assert_eq!(stop, Stop::Fault);
+        assert_eq!(
+            profiler.fault.as_deref(),
+            Some("PC 6 is past the end of the program")
+        );
+        profiler.finish(cpu.cycles());
+        assert_eq!(profiler.functions[ROOT].exclusive, 6);
+    }
+
+    #[test]
+    fn calls_and_returns_are_followed() {
+        let source = "\
+@256\nD=A\n@SP\nM=D\n
+@Main.f$ret0\nD=A\n@retAddr\nM=D\n@Main.f\n0;JMP\n(Main.f$ret0)\n(END)\n@END\n0;JMP\n
+(Main.f)\n@retAddr\nA=M\n0;JMP\n";
+        let program = assemble(source).unwrap();
+        let mut cpu = Cpu::new(program.words);
+        let mut profiler = Profiler::new(&program.labels, cpu.rom());
+        let stop = profiler.run(&mut cpu, &mut Keyboard::default(), 1000);
+        assert_eq!(stop, Stop::Halted);
+        profiler.finish(cpu.cycles());
+        assert_eq!(profiler.functions["Main.f"].calls, 1);
+        assert_eq!(profiler.functions["Main.f"].exclusive, 3);
+        assert_eq!(profiler.folded(), "(top) 10\n(top);Main.f 3\n");
+    }
+}
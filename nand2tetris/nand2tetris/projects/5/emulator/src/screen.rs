@@ -0,0 +1,248 @@
+use std::path::Path;
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+// words in one row of pixels
+const ROW_WORDS: usize = WIDTH / 16;
+
+// Ways to save the screen, picked from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    // binary P4 bitmap, 1 is black like the screen memory
+    Pbm,
+    // 1 bit greyscale
+    Png,
+    // one character per pixel, '#' black and '.' white, for text diffs
+    Ascii,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let format = match path.extension()?.to_str()? {
+            "pbm" => ImageFormat::Pbm,
+            "png" => ImageFormat::Png,
+            "txt" => ImageFormat::Ascii,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn encode(&self, screen: &[u16]) -> Vec<u8> {
+        match self {
+            ImageFormat::Pbm => pbm(screen),
+            ImageFormat::Png => png(screen),
+            ImageFormat::Ascii => ascii(screen, 1, 1).into_bytes(),
+        }
+    }
+}
+
+// Black pixels are 1s and the least significant bit of a word is its
+// leftmost pixel
+pub fn pixel(screen: &[u16], x: usize, y: usize) -> bool {
+    (screen[y * ROW_WORDS + x / 16] >> (x % 16)) & 1 != 0
+}
+
+// Each character covers a block of pixels: '.' when none are black, '#'
+// when at least half are and '+' otherwise. Terminal cells are about twice
+// as tall as wide so 4 by 8 blocks keep the shape in 128 columns.
+pub fn ascii(screen: &[u16], block_width: usize, block_height: usize) -> String {
+    let block_width = block_width.max(1);
+    let block_height = block_height.max(1);
+    let full = block_width * block_height;
+    let mut text = String::new();
+    for top in (0..HEIGHT).step_by(block_height) {
+        for left in (0..WIDTH).step_by(block_width) {
+            let mut black = 0;
+            for y in top..(top + block_height).min(HEIGHT) {
+                for x in left..(left + block_width).min(WIDTH) {
+                    black += pixel(screen, x, y) as usize;
+                }
+            }
+            text.push(match black {
+                0 => '.',
+                n if n * 2 >= full => '#',
+                _ => '+',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+// Rows of 8 pixels to a byte, leftmost pixel in the high bit
+fn packed_rows(screen: &[u16], black: bool) -> Vec<Vec<u8>> {
+    (0..HEIGHT)
+        .map(|y| {
+            (0..WIDTH / 8)
+                .map(|byte| {
+                    (0..8).fold(0, |bits, i| {
+                        let on = pixel(screen, byte * 8 + i, y) == black;
+                        bits << 1 | on as u8
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn pbm(screen: &[u16]) -> Vec<u8> {
+    let mut bytes = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    for row in packed_rows(screen, true) {
+        bytes.extend(row);
+    }
+    bytes
+}
+
+// PNG with the image data in stored (uncompressed) deflate blocks, about 16K
+// for the whole screen and no compressor needed
+fn png(screen: &[u16]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(HEIGHT * (WIDTH / 8 + 1));
+    // greyscale 1 is white, each row starts with filter type 0
+    for row in packed_rows(screen, false) {
+        raw.push(0);
+        raw.extend(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xFFFF).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        let len = block.len() as u16;
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(*block);
+    }
+    zlib.extend(adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    // bit depth 1, greyscale, deflate, no filtering extensions, no interlace
+    header.extend([1, 0, 0, 0, 0]);
+
+    let mut bytes = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+    png_chunk(&mut bytes, b"IHDR", &header);
+    png_chunk(&mut bytes, b"IDAT", &zlib);
+    png_chunk(&mut bytes, b"IEND", &[]);
+    bytes
+}
+
+fn png_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend((data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend(kind);
+    bytes.extend(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend(crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a black pixel in the top left corner and the rightmost pixel of the
+    // first word on the last row
+    fn screen() -> Vec<u16> {
+        let mut screen = vec![0; HEIGHT * ROW_WORDS];
+        screen[0] = 1;
+        screen[(HEIGHT - 1) * ROW_WORDS] = 0x8000;
+        screen
+    }
+
+    #[test]
+    fn bit_zero_is_the_leftmost_pixel() {
+        let screen = screen();
+        assert!(pixel(&screen, 0, 0));
+        assert!(!pixel(&screen, 1, 0));
+        assert!(!pixel(&screen, 15, 0));
+        assert!(pixel(&screen, 15, HEIGHT - 1));
+        assert!(!pixel(&screen, 0, HEIGHT - 1));
+
+        let pbm = ImageFormat::Pbm.encode(&screen);
+        let header = format!("P4\n{} {}\n", WIDTH, HEIGHT);
+        assert_eq!(&pbm[header.len()..header.len() + 2], [0x80, 0]);
+        assert_eq!(&pbm[pbm.len() - WIDTH / 8..][..2], [0, 0x01]);
+
+        let text = String::from_utf8(ImageFormat::Ascii.encode(&screen)).unwrap();
+        assert!(text.starts_with("#..............."));
+        assert!(text.lines().last().unwrap().starts_with("...............#"));
+    }
+
+    #[test]
+    fn checksums_match_the_reference_values() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn png_chunks_and_zlib_stream_are_valid() {
+        let screen = screen();
+        let png = ImageFormat::Png.encode(&screen);
+        assert_eq!(
+            png[..8],
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']
+        );
+
+        let mut chunks = Vec::new();
+        let mut rest = &png[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let body = &rest[4..8 + len];
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc, "{}", String::from_utf8_lossy(&body[..4]));
+            chunks.push((&body[..4], &body[4..]));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 2, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+
+        // undo the stored deflate blocks
+        let zlib = chunks[1].1;
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        let mut raw = Vec::new();
+        let mut at = 2;
+        loop {
+            let last = zlib[at] & 1 != 0;
+            let len = u16::from_le_bytes([zlib[at + 1], zlib[at + 2]]);
+            let nlen = u16::from_le_bytes([zlib[at + 3], zlib[at + 4]]);
+            assert_eq!(nlen, !len);
+            raw.extend(&zlib[at + 5..at + 5 + len as usize]);
+            at += 5 + len as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(zlib[at..], adler32(&raw).to_be_bytes());
+
+        // each row is a filter byte then white as 1s
+        let row = WIDTH / 8 + 1;
+        assert_eq!(raw.len(), HEIGHT * row);
+        assert_eq!(raw[..3], [0, 0x7F, 0xFF]);
+        assert_eq!(raw[(HEIGHT - 1) * row..][..3], [0, 0xFF, 0xFE]);
+    }
+}
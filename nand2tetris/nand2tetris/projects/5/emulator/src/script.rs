@@ -0,0 +1,806 @@
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::cpu::Cpu;
+use crate::cpu::alu;
+use crate::cpu::jumps;
+use crate::loader::load_rom;
+
+#[derive(Debug)]
+pub struct ScriptError {
+    pub path: String,
+    // 0 when the script couldn't be read at all
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            writeln!(f, "error: {}: {}", self.path, self.message)
+        } else {
+            writeln!(f, "error: {}:{}: {}", self.path, self.line, self.message)
+        }
+    }
+}
+
+// A line of output that differs from the compare file, None past the end
+// of either one
+#[derive(Debug)]
+pub struct Mismatch {
+    // counting the header as line 1
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // where the output went, None without an output-file command
+    pub out_path: Option<PathBuf>,
+    // None without a compare-to command
+    pub cmp_path: Option<PathBuf>,
+    // output lines including the header
+    pub lines: usize,
+    pub mismatches: Vec<Mismatch>,
+    pub echoes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    // a command name, argument or quoted echo text
+    Word(String),
+    // `,` `;` or `!`, the simulators only differ in when they pause
+    End,
+    Open,
+    Close,
+}
+
+#[derive(Debug)]
+enum Command {
+    // words of the command and the line it starts on
+    Simple(Vec<String>, usize),
+    Repeat(u64, Vec<Command>),
+}
+
+// One column of the output-list, `name%FMTleft.len.right`
+#[derive(Debug)]
+struct Column {
+    name: String,
+    format: char,
+    pad_left: usize,
+    len: usize,
+    pad_right: usize,
+}
+
+// CPU.hdl driven through its pins. Like the simulator's built in registers,
+// tick latches the next A, D and PC and tock puts them on the outputs, so
+// ARegister[], DRegister[] and PC[] change at tick but addressM and pc
+// only change at tock.
+#[derive(Debug, Default)]
+struct Chip {
+    in_m: u16,
+    instruction: u16,
+    reset: bool,
+    a: u16,
+    d: u16,
+    pc: u16,
+    next_a: u16,
+    next_d: u16,
+    next_pc: u16,
+}
+
+#[derive(Debug)]
+enum Target {
+    // `load Prog.asm` or `load Prog.hack`, the CPU emulator
+    Program(Cpu),
+    // `load Computer.hdl` then `ROM32K load Prog.hack`
+    Computer { cpu: Cpu, reset: bool },
+    // `load CPU.hdl`
+    Chip(Chip),
+}
+
+struct Runner {
+    // files named by the script are relative to it
+    dir: PathBuf,
+    target: Option<Target>,
+    // clock cycles finished and whether the current one is past its tick
+    time: u64,
+    ticked: bool,
+    columns: Vec<Column>,
+    out: Vec<String>,
+    report: Report,
+    compare: Vec<String>,
+}
+
+// Runs a nand2tetris .tst script the way the CPU emulator and hardware
+// simulator would, writes its output-file and compares it line by line
+// with its compare-to file, where a `*` in the compare file matches
+// anything. The chips are built in, CPU.hdl and Computer.hdl are recognised
+// by name and their HDL is never read.
+pub fn run_script(path: &Path) -> Result<Report, ScriptError> {
+    let error = |line, message| ScriptError {
+        path: path.to_string_lossy().to_string(),
+        line,
+        message,
+    };
+    let script = fs::read_to_string(path).map_err(|e| error(0, format!("can't read it: {}", e)))?;
+    let tokens = tokenize(&script).map_err(|(line, message)| error(line, message))?;
+    let mut pos = 0;
+    let commands =
+        parse_block(&tokens, &mut pos, false).map_err(|(line, message)| error(line, message))?;
+
+    let mut runner = Runner {
+        dir: path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        target: None,
+        time: 0,
+        ticked: false,
+        columns: Vec::new(),
+        out: Vec::new(),
+        report: Report::default(),
+        compare: Vec::new(),
+    };
+    runner
+        .run(&commands)
+        .map_err(|(line, message)| error(line, message))?;
+
+    let mut report = runner.report;
+    if let Some(out_path) = &report.out_path {
+        let mut text = runner.out.join("\n");
+        text.push('\n');
+        fs::write(out_path, text).map_err(|e| {
+            let message = format!("can't write {}: {}", out_path.to_string_lossy(), e);
+            error(0, message)
+        })?;
+    }
+    if report.cmp_path.is_some() {
+        for line in 0..runner.out.len().max(runner.compare.len()) {
+            let expected = runner.compare.get(line);
+            let actual = runner.out.get(line);
+            let same = match (expected, actual) {
+                (Some(expected), Some(actual)) => matches(expected, actual),
+                _ => false,
+            };
+            if !same {
+                report.mismatches.push(Mismatch {
+                    line: line + 1,
+                    expected: expected.cloned(),
+                    actual: actual.cloned(),
+                });
+            }
+        }
+    }
+    report.lines = runner.out.len();
+    Ok(report)
+}
+
+fn matches(expected: &str, actual: &str) -> bool {
+    expected.chars().count() == actual.chars().count()
+        && expected
+            .chars()
+            .zip(actual.chars())
+            .all(|(e, a)| e == '*' || e == a)
+}
+
+fn tokenize(script: &str) -> Result<Vec<(Token, usize)>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = script.chars().peekable();
+    let mut line = 1;
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\n' => line += 1,
+            c if c.is_whitespace() => {}
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let start = line;
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            prev = c;
+                        }
+                        None => return Err((start, "`/*` comment is never closed".to_string())),
+                    }
+                }
+            }
+            '"' => {
+                let start = line;
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => return Err((start, "string is never closed".to_string())),
+                    }
+                }
+                tokens.push((Token::Word(text), start));
+            }
+            ',' | ';' | '!' => tokens.push((Token::End, line)),
+            '{' => tokens.push((Token::Open, line)),
+            '}' => tokens.push((Token::Close, line)),
+            _ => {
+                let mut word = ch.to_string();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !",;!{}\"".contains(*c))
+                {
+                    word.push(c);
+                }
+                tokens.push((Token::Word(word), line));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// Commands up to the `}` closing a repeat, or to the end of the script
+fn parse_block(
+    tokens: &[(Token, usize)],
+    pos: &mut usize,
+    nested: bool,
+) -> Result<Vec<Command>, (usize, String)> {
+    let mut commands = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut start = 0;
+    while let Some((token, line)) = tokens.get(*pos) {
+        *pos += 1;
+        match token {
+            Token::Word(word) => {
+                if words.is_empty() {
+                    start = *line;
+                }
+                words.push(word.clone());
+            }
+            Token::End => {
+                if !words.is_empty() {
+                    commands.push(Command::Simple(mem::take(&mut words), start));
+                }
+            }
+            Token::Open => {
+                let count = match words.iter().map(|w| w.as_str()).collect::<Vec<_>>()[..] {
+                    ["repeat", count] => count.parse::<u64>().map_err(|_| {
+                        (start, format!("expected a repeat count, found `{}`", count))
+                    })?,
+                    ["repeat"] => {
+                        return Err((
+                            start,
+                            "`repeat` without a count runs until stopped by hand, give it a count"
+                                .to_string(),
+                        ));
+                    }
+                    ["while", ..] => {
+                        return Err((
+                            start,
+                            "`while` waits on the keyboard and isn't supported".to_string(),
+                        ));
+                    }
+                    _ => return Err((*line, "unexpected `{`".to_string())),
+                };
+                words.clear();
+                let body = parse_block(tokens, pos, true)?;
+                commands.push(Command::Repeat(count, body));
+            }
+            Token::Close => {
+                if !nested {
+                    return Err((*line, "`}` without a matching `{`".to_string()));
+                }
+                if !words.is_empty() {
+                    commands.push(Command::Simple(words, start));
+                }
+                return Ok(commands);
+            }
+        }
+    }
+
+    let last = tokens.last().map(|(_, line)| *line).unwrap_or(1);
+    if nested {
+        return Err((last, "`{` is never closed".to_string()));
+    }
+    if !words.is_empty() {
+        commands.push(Command::Simple(words, start));
+    }
+    Ok(commands)
+}
+
+impl Runner {
+    fn run(&mut self, commands: &[Command]) -> Result<(), (usize, String)> {
+        for command in commands {
+            match command {
+                Command::Simple(words, line) => self.command(words).map_err(|e| (*line, e))?,
+                Command::Repeat(count, body) => {
+                    for _ in 0..*count {
+                        self.run(body)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, words: &[String]) -> Result<(), String> {
+        let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+        match words[..] {
+            ["load", file] => self.load(file),
+            ["ROM32K", "load", file] => match &mut self.target {
+                Some(Target::Computer { cpu, .. }) => {
+                    cpu.set_rom(rom(&self.dir, file)?);
+                    Ok(())
+                }
+                _ => Err("`ROM32K load` needs `load Computer.hdl` first".to_string()),
+            },
+            ["output-file", file] => {
+                self.report.out_path = Some(self.dir.join(file));
+                Ok(())
+            }
+            ["compare-to", file] => {
+                let path = self.dir.join(file);
+                let text = fs::read_to_string(&path)
+                    .map_err(|e| format!("can't read {}: {}", path.to_string_lossy(), e))?;
+                self.compare = text.lines().map(|l| l.trim_end().to_string()).collect();
+                self.report.cmp_path = Some(path);
+                Ok(())
+            }
+            ["output-list", ref columns @ ..] => {
+                self.columns = columns
+                    .iter()
+                    .map(|column| parse_column(column))
+                    .collect::<Result<_, _>>()?;
+                let header: Vec<String> = self.columns.iter().map(|c| c.header()).collect();
+                self.out.push(format!("|{}|", header.join("|")));
+                Ok(())
+            }
+            ["output"] => {
+                let mut cells = Vec::new();
+                for column in &self.columns {
+                    cells.push(column.cell(&self.text(column)?));
+                }
+                self.out.push(format!("|{}|", cells.join("|")));
+                Ok(())
+            }
+            ["set", name, value] => {
+                let value = parse_script_value(value)
+                    .ok_or_else(|| format!("expected a value to set, found `{}`", value))?;
+                self.set(name, value)
+            }
+            ["tick"] => self.tick(),
+            ["tock"] => self.tock(),
+            ["ticktock"] => {
+                self.tick()?;
+                self.tock()
+            }
+            // outputs are worked out when they're read
+            ["eval"] => self.loaded().map(|_| ()),
+            ["echo", text] => {
+                self.report.echoes.push(text.to_string());
+                Ok(())
+            }
+            ["clear-echo"] => Ok(()),
+            ["repeat" | "while", ..] => Err(format!("expected `{{` after `{}`", words[0])),
+            _ => Err(format!("unknown command `{}`", words.join(" "))),
+        }
+    }
+
+    fn load(&mut self, file: &str) -> Result<(), String> {
+        let name = Path::new(file)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let target = match name.as_str() {
+            "Computer.hdl" => Target::Computer {
+                cpu: Cpu::new(Vec::new()),
+                reset: false,
+            },
+            "CPU.hdl" => Target::Chip(Chip::default()),
+            _ if name.ends_with(".hdl") => {
+                return Err(format!(
+                    "`{}` can't be simulated, only CPU.hdl and Computer.hdl are built in",
+                    file
+                ));
+            }
+            _ => Target::Program(Cpu::new(rom(&self.dir, file)?)),
+        };
+        self.target = Some(target);
+        self.time = 0;
+        self.ticked = false;
+        Ok(())
+    }
+
+    fn loaded(&self) -> Result<&Target, String> {
+        self.target.as_ref().ok_or_else(nothing_loaded)
+    }
+
+    fn target(&mut self) -> Result<&mut Target, String> {
+        self.target.as_mut().ok_or_else(nothing_loaded)
+    }
+
+    fn tick(&mut self) -> Result<(), String> {
+        match self.target()? {
+            Target::Program(cpu) => {
+                cpu.step();
+            }
+            Target::Computer { cpu, reset } => {
+                cpu.step();
+                if *reset {
+                    cpu.reset();
+                }
+            }
+            Target::Chip(chip) => chip.tick(),
+        }
+        self.ticked = true;
+        Ok(())
+    }
+
+    // Ends the cycle, ticking first if the script didn't
+    fn tock(&mut self) -> Result<(), String> {
+        if !self.ticked {
+            self.tick()?;
+        }
+        if let Target::Chip(chip) = self.target()? {
+            chip.tock();
+        }
+        self.time += 1;
+        self.ticked = false;
+        Ok(())
+    }
+
+    // The text of a column's value before padding
+    fn text(&self, column: &Column) -> Result<String, String> {
+        if column.name == "time" {
+            let half = if self.ticked { "+" } else { "" };
+            return Ok(match column.format {
+                'S' => format!("{}{}", self.time, half),
+                _ => format_word(self.time as u16, column.format, column.len),
+            });
+        }
+        let word = self.get(&column.name)?;
+        Ok(format_word(word, column.format, column.len))
+    }
+
+    fn get(&self, name: &str) -> Result<u16, String> {
+        let (base, index) = split_index(name)?;
+        let word = match (self.loaded()?, base, index) {
+            (Target::Program(cpu), "A", None) => cpu.a(),
+            (Target::Program(cpu), "D", None) => cpu.d(),
+            (Target::Program(cpu), "PC", None) => cpu.pc(),
+            (Target::Program(cpu), "RAM", Some(addr)) => cpu.read(addr),
+            (Target::Computer { cpu, .. }, "ARegister", None | Some(0)) => cpu.a(),
+            (Target::Computer { cpu, .. }, "DRegister", None | Some(0)) => cpu.d(),
+            (Target::Computer { cpu, .. }, "PC", None | Some(0)) => cpu.pc(),
+            (Target::Computer { cpu, .. }, "RAM16K", Some(addr)) if addr < 16384 => cpu.read(addr),
+            (Target::Computer { reset, .. }, "reset", None) => *reset as u16,
+            (Target::Chip(chip), "inM", None) => chip.in_m,
+            (Target::Chip(chip), "instruction", None) => chip.instruction,
+            (Target::Chip(chip), "reset", None) => chip.reset as u16,
+            (Target::Chip(chip), "outM", None) => chip.out_m(),
+            (Target::Chip(chip), "writeM", None) => chip.write_m() as u16,
+            (Target::Chip(chip), "addressM", None) => chip.a & 0x7FFF,
+            (Target::Chip(chip), "pc", None) => chip.pc,
+            (Target::Chip(chip), "ARegister", None | Some(0)) => chip.next_a,
+            (Target::Chip(chip), "DRegister", None | Some(0)) => chip.next_d,
+            (Target::Chip(chip), "PC", None | Some(0)) => chip.next_pc,
+            _ => return Err(format!("unknown variable `{}`", name)),
+        };
+        Ok(word)
+    }
+
+    fn set(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let (base, index) = split_index(name)?;
+        match (self.target()?, base, index) {
+            (Target::Program(cpu), "A", None) => cpu.set_a(value),
+            (Target::Program(cpu), "D", None) => cpu.set_d(value),
+            (Target::Program(cpu), "PC", None) => cpu.set_pc(value),
+            (Target::Program(cpu), "RAM", Some(addr)) => cpu.write(addr, value),
+            (Target::Computer { cpu, .. }, "ARegister", None | Some(0)) => cpu.set_a(value),
+            (Target::Computer { cpu, .. }, "DRegister", None | Some(0)) => cpu.set_d(value),
+            (Target::Computer { cpu, .. }, "PC", None | Some(0)) => cpu.set_pc(value),
+            (Target::Computer { cpu, .. }, "RAM16K", Some(addr)) if addr < 16384 => {
+                cpu.write(addr, value)
+            }
+            (Target::Computer { reset, .. }, "reset", None) => *reset = value != 0,
+            (Target::Chip(chip), "inM", None) => chip.in_m = value,
+            (Target::Chip(chip), "instruction", None) => chip.instruction = value,
+            (Target::Chip(chip), "reset", None) => chip.reset = value != 0,
+            (Target::Chip(chip), "ARegister", None | Some(0)) => {
+                chip.a = value;
+                chip.next_a = value;
+            }
+            (Target::Chip(chip), "DRegister", None | Some(0)) => {
+                chip.d = value;
+                chip.next_d = value;
+            }
+            (Target::Chip(chip), "PC", None | Some(0)) => {
+                chip.pc = value;
+                chip.next_pc = value;
+            }
+            (Target::Chip(_), "outM" | "writeM" | "addressM" | "pc", None) => {
+                return Err(format!("`{}` is an output and can't be set", name));
+            }
+            _ => return Err(format!("unknown variable `{}`", name)),
+        }
+        Ok(())
+    }
+}
+
+fn nothing_loaded() -> String {
+    "nothing is loaded, start the script with `load`".to_string()
+}
+
+// A .hack, .bin or .asm file named by the script
+fn rom(dir: &Path, file: &str) -> Result<Vec<u16>, String> {
+    load_rom(&dir.join(file))
+        .map_err(|e| format!("can't load `{}`\n{}", file, e.to_string().trim_end()))
+}
+
+impl Chip {
+    fn out_m(&self) -> u16 {
+        let y = if self.instruction & 0x1000 != 0 {
+            self.in_m
+        } else {
+            self.a
+        };
+        alu(self.d, y, self.instruction >> 6)
+    }
+
+    fn is_c(&self) -> bool {
+        self.instruction & 0x8000 != 0
+    }
+
+    fn write_m(&self) -> bool {
+        self.is_c() && self.instruction & 0b001000 != 0
+    }
+
+    fn tick(&mut self) {
+        let word = self.instruction;
+        let out = self.out_m();
+        let is_c = self.is_c();
+        self.next_a = if !is_c {
+            word
+        } else if word & 0b100000 != 0 {
+            out
+        } else {
+            self.a
+        };
+        self.next_d = if is_c && word & 0b010000 != 0 {
+            out
+        } else {
+            self.d
+        };
+        self.next_pc = if self.reset {
+            0
+        } else if is_c && jumps(word, out) {
+            self.a
+        } else {
+            self.pc.wrapping_add(1)
+        };
+    }
+
+    fn tock(&mut self) {
+        self.a = self.next_a;
+        self.d = self.next_d;
+        self.pc = self.next_pc;
+    }
+}
+
+impl Column {
+    fn width(&self) -> usize {
+        self.pad_left + self.len + self.pad_right
+    }
+
+    // The name cut to the column's width and centred
+    fn header(&self) -> String {
+        let name: String = self.name.chars().take(self.width()).collect();
+        let spare = self.width() - name.chars().count();
+        format!(
+            "{}{}{}",
+            " ".repeat(spare / 2),
+            name,
+            " ".repeat(spare - spare / 2)
+        )
+    }
+
+    // Strings are left aligned and numbers right aligned
+    fn cell(&self, text: &str) -> String {
+        let text = if self.format == 'S' {
+            format!("{:<1$}", text, self.len)
+        } else {
+            format!("{:>1$}", text, self.len)
+        };
+        format!(
+            "{}{}{}",
+            " ".repeat(self.pad_left),
+            text,
+            " ".repeat(self.pad_right)
+        )
+    }
+}
+
+// B and X show the low len digits, D and S the signed value
+fn format_word(word: u16, format: char, len: usize) -> String {
+    let bits = match format {
+        'B' => len,
+        'X' => len * 4,
+        _ => return (word as i16).to_string(),
+    };
+    let word = if bits < 16 {
+        word & ((1 << bits) - 1)
+    } else {
+        word
+    };
+    if format == 'B' {
+        format!("{:01$b}", word, len)
+    } else {
+        format!("{:01$X}", word, len)
+    }
+}
+
+// `RAM[0]%D2.6.2`, without a format it's %B1.16.1
+fn parse_column(text: &str) -> Result<Column, String> {
+    let error = || format!("expected `name%FMTleft.len.right`, found `{}`", text);
+    let Some((name, format)) = text.split_once('%') else {
+        return Ok(Column {
+            name: text.to_string(),
+            format: 'B',
+            pad_left: 1,
+            len: 16,
+            pad_right: 1,
+        });
+    };
+    let mut chars = format.chars();
+    let kind = chars
+        .next()
+        .filter(|c| "BDXS".contains(*c))
+        .ok_or_else(error)?;
+    let sizes: Vec<usize> = chars
+        .as_str()
+        .split('.')
+        .map(|n| n.parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| error())?;
+    let [pad_left, len, pad_right] = sizes[..] else {
+        return Err(error());
+    };
+    Ok(Column {
+        name: name.to_string(),
+        format: kind,
+        pad_left,
+        len,
+        pad_right,
+    })
+}
+
+// `RAM16K[5]` as ("RAM16K", Some(5)), `PC[]` and `PC` as ("PC", None)
+fn split_index(name: &str) -> Result<(&str, Option<u16>), String> {
+    let Some((base, rest)) = name.split_once('[') else {
+        return Ok((name, None));
+    };
+    let index = rest
+        .strip_suffix(']')
+        .ok_or_else(|| format!("expected `]` after `{}`", name))?;
+    if index.is_empty() {
+        return Ok((base, None));
+    }
+    let index = parse_script_value(index)
+        .filter(|i| *i < 0x8000)
+        .ok_or_else(|| format!("`{}` isn't a valid index", index))?;
+    Ok((base, Some(index)))
+}
+
+// Decimal, or %B binary, %X hex and %D decimal as the scripts write them
+fn parse_script_value(text: &str) -> Option<u16> {
+    let (radix, digits) = match text.get(..2) {
+        Some("%B") => (2, &text[2..]),
+        Some("%X") => (16, &text[2..]),
+        Some("%D") => (10, &text[2..]),
+        _ => (10, text),
+    };
+    if radix == 10 {
+        return crate::parse_value(digits);
+    }
+    u16::from_str_radix(digits, radix).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    // A fresh directory holding copies of the named project 5 files
+    fn scratch(name: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("emulator-script-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let projects = Path::new(env!("CARGO_MANIFEST_DIR")).join("..");
+        for file in files {
+            fs::copy(projects.join(file), dir.join(file)).unwrap();
+        }
+        dir
+    }
+
+    fn passes(dir: &Path, script: &str) {
+        let report = run_script(&dir.join(script)).unwrap();
+        assert!(report.lines > 1, "{} wrote no output", script);
+        assert!(
+            report.mismatches.is_empty(),
+            "{}: {:?}",
+            script,
+            report.mismatches
+        );
+        let out = fs::read_to_string(report.out_path.unwrap()).unwrap();
+        assert_eq!(out.lines().count(), report.lines);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn error(name: &str, script: &str) -> String {
+        let dir = scratch(name, &[]);
+        fs::write(dir.join("Test.tst"), script).unwrap();
+        let err = run_script(&dir.join("Test.tst")).unwrap_err();
+        fs::remove_dir_all(dir).unwrap();
+        err.to_string()
+    }
+
+    #[test]
+    fn the_cpu_chip_matches_its_compare_file() {
+        passes(&scratch("cpu", &["CPU.tst", "CPU.cmp"]), "CPU.tst");
+    }
+
+    #[test]
+    fn the_computer_runs_max_like_its_compare_file() {
+        let dir = scratch("max", &["ComputerMax.tst", "ComputerMax.cmp", "Max.hack"]);
+        passes(&dir, "ComputerMax.tst");
+    }
+
+    #[test]
+    fn unknown_commands_are_errors() {
+        let message = error("unknown", "load CPU.hdl,\nfrobnicate 3;\n");
+        assert!(
+            message.ends_with(":2: unknown command `frobnicate 3`\n"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn outputs_and_unknown_names_cant_be_set() {
+        let message = error("output", "load CPU.hdl,\nset outM 3,\n");
+        assert!(
+            message.contains(":2: `outM` is an output and can't be set"),
+            "{}",
+            message
+        );
+        let message = error("unknown-set", "load CPU.hdl,\nset RAM[3] 1,\n");
+        assert!(
+            message.contains(":2: unknown variable `RAM[3]`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn mismatches_are_reported_by_line() {
+        let dir = scratch("mismatch", &["Max.hack"]);
+        let script = "load Max.hack,\noutput-file T.out,\ncompare-to T.cmp,\n\
+            output-list RAM[0]%D1.6.1;\nset RAM[0] 7,\noutput;\nset RAM[0] 8,\noutput;\n";
+        fs::write(dir.join("T.tst"), script).unwrap();
+        fs::write(dir.join("T.cmp"), "| RAM[0] |\n|      7 |\n|      9 |\n").unwrap();
+        let report = run_script(&dir.join("T.tst")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        let mismatch = &report.mismatches[0];
+        assert_eq!(mismatch.line, 3);
+        assert_eq!(mismatch.expected.as_deref(), Some("|      9 |"));
+        assert_eq!(mismatch.actual.as_deref(), Some("|      8 |"));
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::Cpu;
+use crate::cpu::MEMORY_SIZE;
+
+const MAGIC: &[u8; 8] = b"HACKSNAP";
+const VERSION: u8 = 1;
+
+// How the VM and the Jack OS lay out RAM, for reading snapshots and diffs
+pub const REGIONS: [(&str, u16, u16); 6] = [
+    ("registers", 0, 15),
+    ("statics", 16, 255),
+    ("stack", 256, 2047),
+    ("heap", 2048, 16383),
+    ("screen", 16384, 24575),
+    ("keyboard", 24576, 24576),
+];
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    // path that couldn't be read or written
+    Io(String, io::Error),
+    // path and what is wrong with its contents
+    Format(String, String),
+    // hash of the snapshot's program and of the one loaded
+    WrongProgram(u64, u64),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(path, e) => writeln!(f, "error: can't access {}: {}", path, e),
+            SnapshotError::Format(path, problem) => {
+                writeln!(f, "error: {} isn't a snapshot: {}", path, problem)
+            }
+            SnapshotError::WrongProgram(saved, loaded) => writeln!(
+                f,
+                "error: snapshot is of program {:016x} but {:016x} is loaded",
+                saved, loaded
+            ),
+        }
+    }
+}
+
+// Everything a Hack machine is, enough to carry on a run exactly where it
+// was left, and the keyboard input that got it there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub rom_hash: u64,
+    pub rom_len: usize,
+    pub extended: bool,
+    pub cycles: u64,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub ram: Vec<u16>,
+    // cycle and new KBD value of each change, see Keyboard::recorded
+    pub keys: Vec<(u64, u16)>,
+}
+
+impl Snapshot {
+    pub fn take(cpu: &Cpu, keys: &[(u64, u16)]) -> Self {
+        Self {
+            rom_hash: rom_hash(cpu.rom()),
+            rom_len: cpu.rom().len(),
+            extended: cpu.extended(),
+            cycles: cpu.cycles(),
+            pc: cpu.pc(),
+            a: cpu.a(),
+            d: cpu.d(),
+            ram: cpu.ram().to_vec(),
+            keys: keys.to_vec(),
+        }
+    }
+
+    // Puts the CPU back in the saved state, it must hold the same program
+    pub fn restore(&self, cpu: &mut Cpu) -> Result<(), SnapshotError> {
+        let loaded = rom_hash(cpu.rom());
+        if loaded != self.rom_hash {
+            return Err(SnapshotError::WrongProgram(self.rom_hash, loaded));
+        }
+        cpu.set_extended(self.extended);
+        cpu.set_cycles(self.cycles);
+        cpu.set_pc(self.pc);
+        cpu.set_a(self.a);
+        cpu.set_d(self.d);
+        for (addr, value) in self.ram.iter().enumerate() {
+            cpu.write(addr as u16, *value);
+        }
+        Ok(())
+    }
+
+    // Little endian throughout: the magic and version, a flags byte, the ROM
+    // hash and length, cycles, PC, A and D, then RAM as runs of zeros each
+    // followed by a run of literal words, then the key changes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.push(self.extended as u8);
+        bytes.extend(self.rom_hash.to_le_bytes());
+        bytes.extend((self.rom_len as u32).to_le_bytes());
+        bytes.extend(self.cycles.to_le_bytes());
+        for word in [self.pc, self.a, self.d] {
+            bytes.extend(word.to_le_bytes());
+        }
+
+        let mut at = 0;
+        while at < self.ram.len() {
+            let zeros = self.ram[at..].iter().take_while(|w| **w == 0).count();
+            at += zeros;
+            let words = self.ram[at..].iter().take_while(|w| **w != 0).count();
+            bytes.extend((zeros as u16).to_le_bytes());
+            bytes.extend((words as u16).to_le_bytes());
+            for word in &self.ram[at..at + words] {
+                bytes.extend(word.to_le_bytes());
+            }
+            at += words;
+        }
+
+        bytes.extend((self.keys.len() as u32).to_le_bytes());
+        for (cycle, key) in &self.keys {
+            bytes.extend(cycle.to_le_bytes());
+            bytes.extend(key.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, at: 0 };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("it doesn't start with HACKSNAP".to_string());
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(format!("version {} isn't supported", version));
+        }
+        let extended = reader.take(1)?[0] != 0;
+        let rom_hash = reader.u64()?;
+        let rom_len = reader.u32()? as usize;
+        let cycles = reader.u64()?;
+        let (pc, a, d) = (reader.u16()?, reader.u16()?, reader.u16()?);
+
+        let mut ram = Vec::with_capacity(MEMORY_SIZE);
+        while ram.len() < MEMORY_SIZE {
+            let zeros = reader.u16()? as usize;
+            let words = reader.u16()? as usize;
+            if ram.len() + zeros + words > MEMORY_SIZE {
+                return Err("its RAM is larger than 32K".to_string());
+            }
+            ram.resize(ram.len() + zeros, 0);
+            for _ in 0..words {
+                ram.push(reader.u16()?);
+            }
+        }
+
+        let count = reader.u32()? as usize;
+        let mut keys = Vec::new();
+        for _ in 0..count {
+            keys.push((reader.u64()?, reader.u16()?));
+        }
+        if reader.at != bytes.len() {
+            return Err(format!("{} bytes are left over", bytes.len() - reader.at));
+        }
+        Ok(Self {
+            rom_hash,
+            rom_len,
+            extended,
+            cycles,
+            pc,
+            a,
+            d,
+            ram,
+            keys,
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        fs::write(path, self.encode())
+            .map_err(|e| SnapshotError::Io(path.to_string_lossy().to_string(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let name = path.to_string_lossy().to_string();
+        let bytes = fs::read(path).map_err(|e| SnapshotError::Io(name.clone(), e))?;
+        Snapshot::decode(&bytes).map_err(|problem| SnapshotError::Format(name, problem))
+    }
+
+    // The registers, the VM pointers and how much of each region is in use
+    pub fn info(&self) -> String {
+        let mut text = format!(
+            "program {:016x}, {} words{}\n",
+            self.rom_hash,
+            self.rom_len,
+            if self.extended { ", extended" } else { "" }
+        );
+        text.push_str(&format!(
+            "cycles {}  PC {}  A {}  D {}\n",
+            self.cycles, self.pc, self.a, self.d as i16
+        ));
+        text.push_str(&format!(
+            "SP {}  LCL {}  ARG {}  THIS {}  THAT {}\n",
+            self.ram[0], self.ram[1], self.ram[2], self.ram[3], self.ram[4]
+        ));
+        for (name, from, to) in REGIONS {
+            let used = self.ram[from as usize..=to as usize]
+                .iter()
+                .filter(|w| **w != 0)
+                .count();
+            text.push_str(&format!(
+                "{:<9}  {:5}-{:<5}  {} nonzero words\n",
+                name, from, to, used
+            ));
+        }
+        text.push_str(&format!("{} key changes recorded\n", self.keys.len()));
+        text
+    }
+}
+
+// Compares two snapshots region by region, listing up to `limit` changed
+// words in each. Empty when they are the same machine state.
+pub fn diff(old: &Snapshot, new: &Snapshot, limit: usize) -> String {
+    let mut text = String::new();
+    if old.rom_hash != new.rom_hash {
+        text.push_str(&format!(
+            "program: {:016x} -> {:016x}\n",
+            old.rom_hash, new.rom_hash
+        ));
+    }
+    if old.cycles != new.cycles {
+        text.push_str(&format!("cycles: {} -> {}\n", old.cycles, new.cycles));
+    }
+    for (name, old, new) in [
+        ("PC", old.pc, new.pc),
+        ("A", old.a, new.a),
+        ("D", old.d, new.d),
+    ] {
+        if old != new {
+            text.push_str(&format!("{}: {} -> {}\n", name, old as i16, new as i16));
+        }
+    }
+
+    for (name, from, to) in REGIONS {
+        let changed: Vec<usize> = (from as usize..=to as usize)
+            .filter(|addr| old.ram[*addr] != new.ram[*addr])
+            .collect();
+        if changed.is_empty() {
+            continue;
+        }
+        text.push_str(&format!(
+            "{} {}-{}: {} word{} differ\n",
+            name,
+            from,
+            to,
+            changed.len(),
+            if changed.len() == 1 { "" } else { "s" }
+        ));
+        for addr in changed.iter().take(limit) {
+            text.push_str(&format!(
+                "  RAM[{}]: {} -> {}\n",
+                addr, old.ram[*addr] as i16, new.ram[*addr] as i16
+            ));
+        }
+        if changed.len() > limit {
+            text.push_str(&format!("  ... {} more\n", changed.len() - limit));
+        }
+    }
+    if old.keys != new.keys {
+        text.push_str(&format!(
+            "keys: {} -> {} changes recorded\n",
+            old.keys.len(),
+            new.keys.len()
+        ));
+    }
+    text
+}
+
+// 64 bit FNV-1a over the words, to tell programs apart
+pub fn rom_hash(rom: &[u16]) -> u64 {
+    let mut hash = 0xCBF2_9CE4_8422_2325u64;
+    for byte in rom.iter().flat_map(|word| word.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.at + len;
+        let slice = self
+            .bytes
+            .get(self.at..end)
+            .ok_or_else(|| "it ends too soon".to_string())?;
+        self.at = end;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().expect("took 4 bytes")))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().expect("took 8 bytes")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::loader::load_rom;
+
+    // where the RAM runs start, after the magic, version, flags, hash,
+    // length, cycles and registers
+    const HEADER_LEN: usize = 36;
+
+    fn pong() -> Cpu {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../6/pong/Pong.asm");
+        Cpu::new(load_rom(&path).unwrap())
+    }
+
+    #[test]
+    fn snapshots_decode_to_what_was_encoded() {
+        let mut cpu = pong();
+        cpu.run(20_000);
+        let snapshot = Snapshot::take(&cpu, &[(5, 130), (900, 0)]);
+        assert_eq!(Snapshot::decode(&snapshot.encode()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn zeroed_ram_is_a_single_run() {
+        let snapshot = Snapshot::take(&Cpu::new(vec![0; 4]), &[]);
+        let bytes = snapshot.encode();
+        // one run of 32768 zeros and no words, then no keys
+        assert_eq!(bytes[HEADER_LEN..], [0x00, 0x80, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(Snapshot::decode(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn bad_input_is_rejected() {
+        let bytes = Snapshot::take(&Cpu::new(vec![0; 4]), &[(1, 2)]).encode();
+        assert_eq!(
+            Snapshot::decode(&bytes[..bytes.len() - 1]),
+            Err("it ends too soon".to_string())
+        );
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            Snapshot::decode(&longer),
+            Err("1 bytes are left over".to_string())
+        );
+        let mut oversized = bytes.clone();
+        oversized[HEADER_LEN..HEADER_LEN + 2].copy_from_slice(&32769u16.to_le_bytes());
+        assert_eq!(
+            Snapshot::decode(&oversized),
+            Err("its RAM is larger than 32K".to_string())
+        );
+        let mut wrong = bytes;
+        wrong[0] = b'X';
+        assert!(Snapshot::decode(&wrong).is_err());
+    }
+
+    #[test]
+    fn a_restored_run_carries_on_exactly() {
+        let mut straight = pong();
+        straight.run(50_000);
+
+        let mut first = pong();
+        first.run(20_000);
+        let bytes = Snapshot::take(&first, &[]).encode();
+        let mut resumed = pong();
+        Snapshot::decode(&bytes)
+            .unwrap()
+            .restore(&mut resumed)
+            .unwrap();
+        resumed.run(30_000);
+
+        assert_eq!(resumed.cycles(), straight.cycles());
+        assert_eq!(
+            (resumed.pc(), resumed.a(), resumed.d()),
+            (straight.pc(), straight.a(), straight.d())
+        );
+        assert!(resumed.ram() == straight.ram());
+    }
+
+    #[test]
+    fn snapshots_only_restore_into_their_program() {
+        let snapshot = Snapshot::take(&pong(), &[]);
+        let mut other = Cpu::new(vec![0; 4]);
+        assert!(matches!(
+            snapshot.restore(&mut other),
+            Err(SnapshotError::WrongProgram(..))
+        ));
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+
+use assembler::SymbolTable;
+use assembler::instruction::Instruction;
+
+use crate::cpu::Cpu;
+use crate::cpu::KBD;
+use crate::cpu::Stop;
+use crate::keyboard::Keyboard;
+
+// One executed instruction and the registers after it
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    // counting from 1
+    pub cycle: u64,
+    pub pc: u16,
+    pub word: u16,
+    pub a: u16,
+    pub d: u16,
+    pub write: Option<(u16, u16)>,
+}
+
+impl Record {
+    // `cycle  pc  word  mnemonic  A= D=  RAM[addr]=value`
+    pub fn line(&self) -> String {
+        let mnemonic = match Instruction::decode(self.word) {
+            Some(instruction) => instruction.to_string(),
+            None => "???".to_string(),
+        };
+        let mut line = format!(
+            "{:9}  {:5}  {:016b}  {:<14}  A={:<5}  D={:<6}",
+            self.cycle, self.pc, self.word, mnemonic, self.a, self.d as i16
+        );
+        if let Some((addr, value)) = self.write {
+            line.push_str(&format!("  RAM[{}]={}", addr, value as i16));
+        }
+        line.trim_end().to_string()
+    }
+}
+
+// Writes a line per executed instruction, optionally only for some ROM
+// ranges. With a ring size only the last that many records are kept and
+// written once the run stops, for finding what led up to a halt, a fault or
+// a program that never stops.
+pub struct Tracer<W: Write> {
+    out: W,
+    // inclusive ROM ranges, empty traces everything
+    ranges: Vec<(u16, u16)>,
+    ring: Option<(usize, VecDeque<Record>)>,
+    pub fault: Option<String>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, ranges: Vec<(u16, u16)>, ring_size: Option<usize>) -> Self {
+        Self {
+            out,
+            ranges,
+            ring: ring_size.map(|size| (size, VecDeque::with_capacity(size))),
+            fault: None,
+        }
+    }
+
+    // Keyboard::run a step at a time, stopping early on an illegal state:
+    // running past the end of the program, a word that isn't an instruction
+    // or a store the memory map ignores
+    pub fn run(&mut self, cpu: &mut Cpu, keyboard: &mut Keyboard, until: u64) -> io::Result<Stop> {
+        while cpu.cycles() < until {
+            keyboard.apply(cpu);
+            if cpu.is_halted() {
+                return Ok(Stop::Halted);
+            }
+            let pc = cpu.pc();
+            let Some(word) = cpu.rom().get(pc as usize).copied() else {
+                self.fault = Some(format!("PC {} is past the end of the program", pc));
+                return Ok(Stop::Fault);
+            };
+            let extended = matches!(
+                Instruction::decode(word),
+                Some(Instruction::C { comp, .. }) if comp.is_extended()
+            );
+            if Instruction::decode(word).is_none() || (extended && !cpu.extended()) {
+                self.fault = Some(format!(
+                    "{:016b} at ROM {} isn't a Hack instruction",
+                    word, pc
+                ));
+                return Ok(Stop::Fault);
+            }
+
+            let step = cpu.step();
+            self.record(Record {
+                cycle: cpu.cycles(),
+                pc: step.pc,
+                word: step.word,
+                a: cpu.a(),
+                d: cpu.d(),
+                write: step.write,
+            })?;
+            if let Some((addr, value)) = step.write
+                && addr >= KBD
+            {
+                self.fault = Some(format!(
+                    "ROM {} stored {} at {}, past the end of the memory map",
+                    pc, value as i16, addr
+                ));
+                return Ok(Stop::Fault);
+            }
+        }
+        Ok(if cpu.is_halted() {
+            Stop::Halted
+        } else {
+            Stop::CycleLimit
+        })
+    }
+
+    fn record(&mut self, record: Record) -> io::Result<()> {
+        let traced = self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(from, to)| (*from..=*to).contains(&record.pc));
+        if !traced {
+            return Ok(());
+        }
+        match &mut self.ring {
+            Some((size, ring)) => {
+                if ring.len() == *size {
+                    ring.pop_front();
+                }
+                if *size > 0 {
+                    ring.push_back(record);
+                }
+                Ok(())
+            }
+            None => writeln!(self.out, "{}", record.line()),
+        }
+    }
+
+    // Writes out the ring and why the run stopped
+    pub fn finish(&mut self, stop: Stop, cpu: &Cpu) -> io::Result<()> {
+        if let Some((_, ring)) = &mut self.ring {
+            for record in ring.drain(..) {
+                writeln!(self.out, "{}", record.line())?;
+            }
+        }
+        match stop {
+            Stop::Halted => writeln!(self.out, "// halted at PC {}", cpu.pc())?,
+            Stop::CycleLimit => writeln!(
+                self.out,
+                "// still running at PC {} after {} cycles",
+                cpu.pc(),
+                cpu.cycles()
+            )?,
+            Stop::Fault => writeln!(
+                self.out,
+                "// stopped: {}",
+                self.fault.as_deref().unwrap_or("")
+            )?,
+        }
+        self.out.flush()
+    }
+}
+
+// `FROM-TO` with numbers or labels, a single address, or a label alone
+// which covers it up to the next label outside its own scope, so
+// `Main.fibonacci` includes `Main.fibonacci$N_LT_2` and the return labels
+// of the calls it makes
+pub fn parse_range(spec: &str, labels: &SymbolTable, rom_len: usize) -> Option<(u16, u16)> {
+    let address = |text: &str| -> Option<u16> {
+        match text.parse::<u16>() {
+            Ok(addr) => Some(addr),
+            Err(_) => labels.get(text).copied(),
+        }
+    };
+    if let Some((from, to)) = spec.split_once('-') {
+        return Some((address(from)?, address(to)?));
+    }
+    if let Ok(addr) = spec.parse::<u16>() {
+        return Some((addr, addr));
+    }
+
+    let start = *labels.get(spec)?;
+    let scoped = |name: &str| {
+        name.strip_prefix(spec)
+            .is_some_and(|rest| rest.starts_with('$') || rest.starts_with('.'))
+    };
+    let end = labels
+        .iter()
+        .filter(|(name, addr)| **addr > start && !scoped(name))
+        .map(|(_, addr)| *addr - 1)
+        .min()
+        .unwrap_or(rom_len.saturating_sub(1) as u16);
+    Some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTDOWN: &str = "@5\nD=A\n(LOOP)\nD=D-1\n@LOOP\nD;JGT\n(END)\n@END\n0;JMP\n";
+
+    fn trace(ranges: Vec<(u16, u16)>, ring_size: Option<usize>) -> Vec<String> {
+        let program = assembler::assemble(COUNTDOWN).unwrap();
+        let mut cpu = Cpu::new(program.words);
+        let mut keyboard = Keyboard::new(Vec::new());
+        let mut tracer = Tracer::new(Vec::new(), ranges, ring_size);
+        let stop = tracer.run(&mut cpu, &mut keyboard, 1000).unwrap();
+        tracer.finish(stop, &cpu).unwrap();
+        String::from_utf8(tracer.out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn labels(pairs: &[(&str, u16)]) -> SymbolTable {
+        pairs
+            .iter()
+            .map(|(name, addr)| (name.to_string(), *addr))
+            .collect()
+    }
+
+    #[test]
+    fn ranges_from_numbers() {
+        let labels = labels(&[]);
+        assert_eq!(parse_range("3-7", &labels, 20), Some((3, 7)));
+        assert_eq!(parse_range("5", &labels, 20), Some((5, 5)));
+    }
+
+    #[test]
+    fn ranges_from_labels() {
+        let labels = labels(&[
+            ("Main.main", 0),
+            ("Main.fibonacci", 10),
+            ("Main.fibonacci$N_LT_2", 14),
+            ("Main.fibonacci$ret.0", 18),
+            ("Main.fibonacciTwo", 25),
+            ("Sys.init", 30),
+        ]);
+        assert_eq!(
+            parse_range("Main.main-Sys.init", &labels, 40),
+            Some((0, 30))
+        );
+        assert_eq!(
+            parse_range("Main.fibonacci-20", &labels, 40),
+            Some((10, 20))
+        );
+        // a label alone runs up to the next one outside its scope
+        assert_eq!(parse_range("Main.fibonacci", &labels, 40), Some((10, 24)));
+        assert_eq!(parse_range("Main.main", &labels, 40), Some((0, 9)));
+        // or to the end of the ROM
+        assert_eq!(parse_range("Sys.init", &labels, 40), Some((30, 39)));
+    }
+
+    #[test]
+    fn bad_ranges_are_rejected() {
+        let labels = labels(&[("LOOP", 2)]);
+        for spec in ["", "-", "3-", "-3", "LOOP-NOPE", "NOPE", "70000", "1-2-3"] {
+            assert_eq!(parse_range(spec, &labels, 10), None, "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn ranges_filter_the_trace() {
+        let lines = trace(vec![(2, 2)], None);
+        let (last, records) = lines.split_last().unwrap();
+        assert_eq!(last, "// halted at PC 5");
+        assert_eq!(records.len(), 5);
+        assert!(records.iter().all(|line| line.contains(" D=D-1 ")));
+    }
+
+    #[test]
+    fn the_ring_keeps_only_the_last_records() {
+        let full = trace(Vec::new(), None);
+        assert!(full.len() > 4);
+        for size in [0, 1, 3] {
+            let ring = trace(Vec::new(), Some(size));
+            let (last, records) = full.split_last().unwrap();
+            assert_eq!(ring.last(), Some(last));
+            assert_eq!(ring[..ring.len() - 1], records[records.len() - size..]);
+        }
+    }
+}
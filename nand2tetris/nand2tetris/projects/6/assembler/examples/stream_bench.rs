@@ -0,0 +1,52 @@
+// Times the single pass --stream assembler against the two pass one on Pong,
+// the largest program in the projects. Run with
+// cargo run --release --example stream_bench
+use std::fs;
+use std::hint::black_box;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use assembler::AsmOptions;
+use assembler::assemble;
+use assembler::output::OutputFormat;
+use assembler::stream::assemble_stream;
+
+const RUNS: u32 = 20;
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../pong/Pong.asm");
+    let source = fs::read_to_string(path).expect("Can't read Pong.asm");
+    println!("{} lines, {} runs each", source.lines().count(), RUNS);
+
+    // both write the same .hack text to a sink
+    let two_pass = time(|| {
+        let program = assemble(&source).expect("Pong assembles");
+        OutputFormat::Hack
+            .write(&program.words, &mut io::sink())
+            .expect("a sink takes anything");
+        black_box(program.words.len());
+    });
+    let stream = time(|| {
+        let streamed = assemble_stream(
+            source.as_bytes(),
+            OutputFormat::Hack,
+            io::sink(),
+            "Pong.asm",
+            AsmOptions::default(),
+        )
+        .expect("Pong assembles");
+        black_box(streamed.size);
+    });
+    println!("two pass  {:8.2} ms", two_pass);
+    println!("stream    {:8.2} ms", stream);
+}
+
+// Mean milliseconds per run
+fn time(mut run: impl FnMut()) -> f64 {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        run();
+    }
+    start.elapsed().as_secs_f64() * 1000.0 / RUNS as f64
+}
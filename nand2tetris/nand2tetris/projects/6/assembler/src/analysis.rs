@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use crate::SymbolTable;
+use crate::error::AsmError;
+use crate::error::SpanError;
+use crate::instruction::Instruction;
+use crate::instruction::Jump;
+use crate::parser::Token;
+use crate::parser::TokenKind;
+
+#[derive(Debug, Default)]
+struct Usage<'t, 'a> {
+    // first token that used the symbol each way
+    jump: Option<&'t Token<'a>>,
+    data: Option<&'t Token<'a>>,
+}
+
+// Warns about symbols that assembled fine but probably aren't what was meant,
+// instructions has one entry per non label token
+pub fn check_symbol_usage(
+    tokens: &[Token],
+    instructions: &[Instruction],
+    labels: &SymbolTable,
+    variables: &SymbolTable,
+    errors: &mut AsmError,
+) {
+    let code: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| t.kind != TokenKind::Label)
+        .collect();
+    let mut usages: HashMap<&str, Usage> = HashMap::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        let Instruction::A {
+            symbol: Some(symbol),
+            ..
+        } = instruction
+        else {
+            continue;
+        };
+        if !labels.contains_key(symbol) && !variables.contains_key(symbol) {
+            continue;
+        }
+        let usage = usages.entry(symbol).or_default();
+        match instructions.get(i + 1) {
+            Some(Instruction::C { jump, .. }) if *jump != Jump::Null => {
+                usage.jump.get_or_insert(code[i]);
+            }
+            Some(Instruction::C { comp, dest, .. }) if comp.reads_m() || dest.writes_m() => {
+                usage.data.get_or_insert(code[i]);
+            }
+            _ => {}
+        }
+    }
+
+    let mut names: Vec<&&str> = usages.keys().collect();
+    names.sort();
+    for name in names {
+        let usage = &usages[*name];
+        let Some(jump) = usage.jump else {
+            continue;
+        };
+        // the source text may be a shorter `.local` name
+        let len = |token: &Token| token.text.chars().count() - 1;
+        if variables.contains_key(*name) {
+            let err = SpanError::new(jumps_to_variable(name), 1, len(jump));
+            errors.warn_token(jump, err);
+        } else if let Some(data) = usage.data {
+            let err = SpanError::new(jumps_and_data(name, jump.line_num), 1, len(data));
+            errors.warn_token(data, err);
+        }
+    }
+}
+
+// The warnings, shared with the streaming assembler
+
+pub(crate) fn jumps_to_variable(name: &str) -> String {
+    format!(
+        "`{}` is used as a jump target but never defined as a label, it was allocated as a RAM variable",
+        name
+    )
+}
+
+pub(crate) fn jumps_and_data(name: &str, jump_line: usize) -> String {
+    format!(
+        "`{}` is used both as a jump target (line {}) and as a data address",
+        name, jump_line
+    )
+}
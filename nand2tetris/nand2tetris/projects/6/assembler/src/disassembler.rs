@@ -0,0 +1,159 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+use crate::error::AsmError;
+use crate::error::SpanError;
+use crate::instruction::Instruction;
+use crate::instruction::Jump;
+use crate::symbols::SymbolMap;
+
+#[derive(Debug)]
+pub struct Disassembly {
+    pub asm: String,
+    // ROM addresses of words that aren't valid instructions
+    pub undecodable: Vec<u16>,
+}
+
+// How the value loaded by an A instruction gets used by the next instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Usage {
+    JumpTarget,
+    Memory,
+    Constant,
+}
+
+// Parses .hack text, one 16 char binary word per line
+pub fn read_hack(contents: &str) -> Result<Vec<u16>, AsmError> {
+    let mut errors = AsmError::default();
+    let mut words = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        let word = line.trim();
+        if word.is_empty() {
+            continue;
+        }
+        let valid = word.len() == 16 && word.chars().all(|c| c == '0' || c == '1');
+        match u16::from_str_radix(word, 2) {
+            Ok(value) if valid => words.push(value),
+            _ => {
+                let start = line.len() - line.trim_start().len();
+                let cols: Vec<usize> = (start..start + word.chars().count()).collect();
+                let err = SpanError::new(
+                    format!("`{}` is not a 16 bit binary word", word),
+                    0,
+                    cols.len(),
+                );
+                errors.push(idx + 1, line, &cols, err);
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(words)
+}
+
+// labels are only applied to jump targets and RAM names only to memory
+// accesses so constants stay numeric
+pub fn disassemble(words: &[u16], symbols: Option<&SymbolMap>) -> Disassembly {
+    let decoded: Vec<Option<Instruction>> = words.iter().map(|w| Instruction::decode(*w)).collect();
+    let usages: Vec<Usage> = (0..decoded.len()).map(|i| usage_at(&decoded, i)).collect();
+
+    let mut ram_names: HashMap<u16, String> = HashMap::new();
+    let mut rom_names: HashMap<u16, String> = HashMap::new();
+    if let Some(symbols) = symbols {
+        for i in 0..16 {
+            ram_names.insert(i, format!("R{}", i));
+        }
+        ram_names.insert(16384, "SCREEN".to_string());
+        ram_names.insert(24576, "KBD".to_string());
+
+        let mut labels: Vec<(&String, &u16)> = symbols.labels.iter().collect();
+        labels.sort();
+        for (name, value) in labels {
+            rom_names.entry(*value).or_insert_with(|| name.clone());
+        }
+        let mut variables: Vec<(&String, &u16)> = symbols.variables.iter().collect();
+        variables.sort();
+        for (name, value) in variables {
+            ram_names.entry(*value).or_insert_with(|| name.clone());
+        }
+    }
+
+    // synthesize L_n for jump targets nobody named
+    let mut targets = BTreeSet::new();
+    for (i, instruction) in decoded.iter().enumerate() {
+        if let Some(Instruction::A { value, .. }) = instruction
+            && usages[i] == Usage::JumpTarget
+            && (*value as usize) <= words.len()
+        {
+            targets.insert(*value);
+        }
+    }
+    let mut count = 0;
+    for target in targets {
+        rom_names.entry(target).or_insert_with(|| {
+            count += 1;
+            format!("L_{}", count - 1)
+        });
+    }
+
+    let mut asm = String::new();
+    let mut undecodable = Vec::new();
+    for (i, instruction) in decoded.into_iter().enumerate() {
+        let addr = i as u16;
+        if let Some(label) = rom_names.get(&addr) {
+            asm.push_str(&format!("({})\n", label));
+        }
+        let instruction = match instruction {
+            Some(Instruction::A { value, .. }) => {
+                let symbol = match usages[i] {
+                    Usage::JumpTarget => rom_names.get(&value),
+                    Usage::Memory => ram_names.get(&value),
+                    Usage::Constant => match value {
+                        16384 | 24576 => ram_names.get(&value),
+                        _ => None,
+                    },
+                };
+                Instruction::A {
+                    value,
+                    symbol: symbol.cloned(),
+                }
+            }
+            Some(instruction) => instruction,
+            None => {
+                undecodable.push(addr);
+                asm.push_str(&format!(
+                    "// undecodable word {:016b} at {}\n",
+                    words[i], addr
+                ));
+                continue;
+            }
+        };
+        asm.push_str(&format!("{}\n", instruction));
+    }
+    // a label can point one past the last instruction
+    if let Some(label) = rom_names.get(&(words.len() as u16)) {
+        asm.push_str(&format!("({})\n", label));
+    }
+
+    Disassembly { asm, undecodable }
+}
+
+fn usage_at(decoded: &[Option<Instruction>], i: usize) -> Usage {
+    if !matches!(decoded[i], Some(Instruction::A { .. })) {
+        return Usage::Constant;
+    }
+    match decoded.get(i + 1) {
+        Some(Some(Instruction::C { comp, dest, jump })) => {
+            if *jump != Jump::Null {
+                Usage::JumpTarget
+            } else if comp.reads_m() || dest.writes_m() {
+                Usage::Memory
+            } else {
+                Usage::Constant
+            }
+        }
+        _ => Usage::Constant,
+    }
+}
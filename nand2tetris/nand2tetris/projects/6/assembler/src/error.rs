@@ -0,0 +1,186 @@
+use std::fmt;
+
+use crate::parser::Token;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// One problem at a specific spot in the source, line and col are 1-based
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // index into the file list of the error or program
+    pub file: usize,
+    pub line: usize,
+    pub col: usize,
+    pub text: String,
+    pub source: String,
+    pub message: String,
+}
+
+// Every diagnostic found while assembling, files holds the path of each
+// source and include, path is used when there is no file list
+#[derive(Debug, Default)]
+pub struct AsmError {
+    pub path: String,
+    pub files: Vec<String>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+// Error from a parse function, offsets are into the whitespace stripped instruction
+#[derive(Debug)]
+pub struct SpanError {
+    pub message: String,
+    pub at: usize,
+    pub len: usize,
+}
+
+impl SpanError {
+    pub fn new(message: String, at: usize, len: usize) -> Self {
+        Self { message, at, len }
+    }
+}
+
+impl AsmError {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            files: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    // cols maps each char of the stripped instruction back to its column in source
+    pub fn push(&mut self, line_num: usize, source: &str, cols: &[usize], err: SpanError) {
+        self.push_with(Severity::Error, 0, line_num, source, cols, err);
+    }
+
+    pub fn push_in(
+        &mut self,
+        file: usize,
+        line_num: usize,
+        source: &str,
+        cols: &[usize],
+        err: SpanError,
+    ) {
+        self.push_with(Severity::Error, file, line_num, source, cols, err);
+    }
+
+    pub(crate) fn push_token(&mut self, token: &Token, err: SpanError) {
+        let (file, line_num) = (token.file, token.line_num);
+        self.push_with(
+            Severity::Error,
+            file,
+            line_num,
+            token.source,
+            &token.cols,
+            err,
+        );
+    }
+
+    pub(crate) fn warn(&mut self, line_num: usize, source: &str, cols: &[usize], err: SpanError) {
+        self.push_with(Severity::Warning, 0, line_num, source, cols, err);
+    }
+
+    pub(crate) fn warn_token(&mut self, token: &Token, err: SpanError) {
+        let (file, line_num) = (token.file, token.line_num);
+        self.push_with(
+            Severity::Warning,
+            file,
+            line_num,
+            token.source,
+            &token.cols,
+            err,
+        );
+    }
+
+    fn push_with(
+        &mut self,
+        severity: Severity,
+        file: usize,
+        line_num: usize,
+        source: &str,
+        cols: &[usize],
+        err: SpanError,
+    ) {
+        let (col, len) = match cols.get(err.at) {
+            Some(&start) => {
+                let last = err.at + err.len.max(1) - 1;
+                let end = cols.get(last).copied().unwrap_or(start);
+                (start, end - start + 1)
+            }
+            None => (source.chars().count(), 1),
+        };
+        let text: String = source.chars().skip(col).take(len).collect();
+        self.diagnostics.push(Diagnostic {
+            severity,
+            file,
+            line: line_num,
+            col: col + 1,
+            text,
+            source: source.to_string(),
+            message: err.message,
+        });
+    }
+}
+
+// rustc style listing with a caret under the offending text,
+// files gives the path for each diagnostic's file index
+pub fn render(files: &[String], diagnostics: &[Diagnostic]) -> String {
+    let mut sorted: Vec<&Diagnostic> = diagnostics.iter().collect();
+    sorted.sort_by_key(|d| (d.file, d.line, d.col));
+    let mut out = String::new();
+    for diag in sorted {
+        let gutter = " ".repeat(diag.line.to_string().len());
+        let label = match diag.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        out.push_str(&format!("{}: {}\n", label, diag.message));
+        out.push_str(&format!(
+            "{gutter}--> {}:{}:{}\n",
+            files.get(diag.file).map_or("<input>", |p| p.as_str()),
+            diag.line,
+            diag.col
+        ));
+        out.push_str(&format!("{gutter} |\n"));
+        out.push_str(&format!("{} | {}\n", diag.line, diag.source));
+        out.push_str(&format!(
+            "{gutter} | {}{}\n\n",
+            " ".repeat(diag.col - 1),
+            "^".repeat(diag.text.chars().count().max(1))
+        ));
+    }
+    out
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let files = if self.files.is_empty() {
+            std::slice::from_ref(&self.path)
+        } else {
+            &self.files
+        };
+        write!(f, "{}", render(files, &self.diagnostics))?;
+        let count = self
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+        let plural = if count == 1 { "" } else { "s" };
+        writeln!(f, "error: aborting due to {count} previous error{plural}")
+    }
+}
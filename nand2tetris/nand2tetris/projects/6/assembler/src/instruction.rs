@@ -0,0 +1,393 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    // symbol is kept so tools can show @LOOP instead of @12
+    A { value: u16, symbol: Option<String> },
+    C { comp: Comp, dest: Dest, jump: Jump },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comp {
+    Zero,
+    One,
+    NegOne,
+    D,
+    A,
+    M,
+    NotD,
+    NotA,
+    NotM,
+    NegD,
+    NegA,
+    NegM,
+    DPlusOne,
+    APlusOne,
+    MPlusOne,
+    DMinusOne,
+    AMinusOne,
+    MMinusOne,
+    DPlusA,
+    DPlusM,
+    DMinusA,
+    DMinusM,
+    AMinusD,
+    MMinusD,
+    DAndA,
+    DAndM,
+    DOrA,
+    DOrM,
+    // extended Hack ISA shifts, encoded with the 101 prefix
+    ShiftLeftD,
+    ShiftLeftA,
+    ShiftLeftM,
+    ShiftRightD,
+    ShiftRightA,
+    ShiftRightM,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dest {
+    Null,
+    M,
+    D,
+    MD,
+    A,
+    AM,
+    AD,
+    AMD,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jump {
+    Null,
+    Jgt,
+    Jeq,
+    Jge,
+    Jlt,
+    Jne,
+    Jle,
+    Jmp,
+}
+
+impl Instruction {
+    pub fn encode(&self) -> u16 {
+        match self {
+            Instruction::A { value, .. } => *value,
+            Instruction::C { comp, dest, jump } => {
+                let prefix = if comp.is_extended() { 0b101 } else { 0b111 };
+                prefix << 13 | comp.bits() << 6 | dest.bits() << 3 | jump.bits()
+            }
+        }
+    }
+
+    // None when the word isn't a valid Hack instruction, 101 words decode
+    // to the extended shifts
+    pub fn decode(word: u16) -> Option<Instruction> {
+        if word & 0x8000 == 0 {
+            return Some(Instruction::A {
+                value: word,
+                symbol: None,
+            });
+        }
+        let comps = match word >> 13 {
+            0b111 => &COMP_TABLE,
+            0b101 => &EXTENDED_COMP_TABLE,
+            _ => return None,
+        };
+        Some(Instruction::C {
+            comp: comps.by_bits((word >> 6) & 0b1111111)?,
+            dest: Dest::from_bits((word >> 3) & 0b111)?,
+            jump: Jump::from_bits(word & 0b111)?,
+        })
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::A {
+                symbol: Some(symbol),
+                ..
+            } => write!(f, "@{}", symbol),
+            Instruction::A { value, .. } => write!(f, "@{}", value),
+            Instruction::C { comp, dest, jump } => {
+                if *dest != Dest::Null {
+                    write!(f, "{}=", dest.mnemonic())?;
+                }
+                write!(f, "{}", comp.mnemonic())?;
+                if *jump != Jump::Null {
+                    write!(f, ";{}", jump.mnemonic())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// One row of a mnemonic table, name is the spelling written back out and
+// aliases are the other spellings accepted for the same bits
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<T: 'static> {
+    pub value: T,
+    pub bits: u16,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+}
+
+// Maps between mnemonic, field value and encoding in either direction
+#[derive(Debug, Clone, Copy)]
+pub struct Table<T: 'static> {
+    pub entries: &'static [Entry<T>],
+}
+
+impl<T: Copy + PartialEq> Table<T> {
+    pub fn by_name(&self, name: &str) -> Option<T> {
+        self.entries
+            .iter()
+            .find(|e| !name.is_empty() && (e.name == name || e.aliases.contains(&name)))
+            .map(|e| e.value)
+    }
+
+    pub fn by_bits(&self, bits: u16) -> Option<T> {
+        self.entries
+            .iter()
+            .find(|e| e.bits == bits)
+            .map(|e| e.value)
+    }
+
+    pub fn entry(&self, value: T) -> Option<&'static Entry<T>> {
+        self.entries.iter().find(|e| e.value == value)
+    }
+}
+
+const fn entry<T>(
+    value: T,
+    bits: u16,
+    name: &'static str,
+    aliases: &'static [&'static str],
+) -> Entry<T> {
+    Entry {
+        value,
+        bits,
+        name,
+        aliases,
+    }
+}
+
+// a bit followed by the six c bits
+pub const COMP_TABLE: Table<Comp> = Table {
+    entries: &[
+        entry(Comp::Zero, 0b0101010, "0", &[]),
+        entry(Comp::One, 0b0111111, "1", &[]),
+        entry(Comp::NegOne, 0b0111010, "-1", &[]),
+        entry(Comp::D, 0b0001100, "D", &[]),
+        entry(Comp::A, 0b0110000, "A", &[]),
+        entry(Comp::M, 0b1110000, "M", &[]),
+        entry(Comp::NotD, 0b0001101, "!D", &[]),
+        entry(Comp::NotA, 0b0110001, "!A", &[]),
+        entry(Comp::NotM, 0b1110001, "!M", &[]),
+        entry(Comp::NegD, 0b0001111, "-D", &[]),
+        entry(Comp::NegA, 0b0110011, "-A", &[]),
+        entry(Comp::NegM, 0b1110011, "-M", &[]),
+        entry(Comp::DPlusOne, 0b0011111, "D+1", &["1+D"]),
+        entry(Comp::APlusOne, 0b0110111, "A+1", &["1+A"]),
+        entry(Comp::MPlusOne, 0b1110111, "M+1", &["1+M"]),
+        entry(Comp::DMinusOne, 0b0001110, "D-1", &["-1+D"]),
+        entry(Comp::AMinusOne, 0b0110010, "A-1", &["-1+A"]),
+        entry(Comp::MMinusOne, 0b1110010, "M-1", &["-1+M"]),
+        entry(Comp::DPlusA, 0b0000010, "D+A", &["A+D"]),
+        entry(Comp::DPlusM, 0b1000010, "D+M", &["M+D"]),
+        entry(Comp::DMinusA, 0b0010011, "D-A", &["-A+D"]),
+        entry(Comp::DMinusM, 0b1010011, "D-M", &["-M+D"]),
+        entry(Comp::AMinusD, 0b0000111, "A-D", &["-D+A"]),
+        entry(Comp::MMinusD, 0b1000111, "M-D", &["-D+M"]),
+        entry(Comp::DAndA, 0b0000000, "D&A", &["A&D"]),
+        entry(Comp::DAndM, 0b1000000, "D&M", &["M&D"]),
+        entry(Comp::DOrA, 0b0010101, "D|A", &["A|D"]),
+        entry(Comp::DOrM, 0b1010101, "D|M", &["M|D"]),
+    ],
+};
+
+// Shifts from the extended Hack ISA, same layout but after a 101 prefix
+pub const EXTENDED_COMP_TABLE: Table<Comp> = Table {
+    entries: &[
+        entry(Comp::ShiftLeftD, 0b0110000, "D<<", &[]),
+        entry(Comp::ShiftLeftA, 0b0100000, "A<<", &[]),
+        entry(Comp::ShiftLeftM, 0b1100000, "M<<", &[]),
+        entry(Comp::ShiftRightD, 0b0010000, "D>>", &[]),
+        entry(Comp::ShiftRightA, 0b0000000, "A>>", &[]),
+        entry(Comp::ShiftRightM, 0b1000000, "M>>", &[]),
+    ],
+};
+
+pub const DEST_TABLE: Table<Dest> = Table {
+    entries: &[
+        entry(Dest::Null, 0b000, "", &[]),
+        entry(Dest::M, 0b001, "M", &[]),
+        entry(Dest::D, 0b010, "D", &[]),
+        entry(Dest::MD, 0b011, "MD", &["DM"]),
+        entry(Dest::A, 0b100, "A", &[]),
+        entry(Dest::AM, 0b101, "AM", &["MA"]),
+        entry(Dest::AD, 0b110, "AD", &["DA"]),
+        entry(
+            Dest::AMD,
+            0b111,
+            "AMD",
+            &["ADM", "MAD", "MDA", "DAM", "DMA"],
+        ),
+    ],
+};
+
+pub const JUMP_TABLE: Table<Jump> = Table {
+    entries: &[
+        entry(Jump::Null, 0b000, "", &[]),
+        entry(Jump::Jgt, 0b001, "JGT", &[]),
+        entry(Jump::Jeq, 0b010, "JEQ", &[]),
+        entry(Jump::Jge, 0b011, "JGE", &[]),
+        entry(Jump::Jlt, 0b100, "JLT", &[]),
+        entry(Jump::Jne, 0b101, "JNE", &[]),
+        entry(Jump::Jle, 0b110, "JLE", &[]),
+        entry(Jump::Jmp, 0b111, "JMP", &[]),
+    ],
+};
+
+// Accepts the extended shifts too, callers decide whether they're allowed
+pub fn handle_comp_instruct(comp: &str) -> Result<Comp, String> {
+    COMP_TABLE
+        .by_name(comp)
+        .or_else(|| EXTENDED_COMP_TABLE.by_name(comp))
+        .ok_or_else(|| format!("unknown comp instruction `{}`", comp))
+}
+
+pub fn handle_dest_instruct(dest: &str) -> Result<Dest, String> {
+    DEST_TABLE
+        .by_name(dest)
+        .ok_or_else(|| format!("unknown dest instruction `{}`", dest))
+}
+
+pub fn handle_jmp_instruct(jmp: &str) -> Result<Jump, String> {
+    JUMP_TABLE
+        .by_name(jmp)
+        .ok_or_else(|| format!("unknown jmp instruction `{}`", jmp))
+}
+
+impl Comp {
+    pub fn from_bits(bits: u16) -> Option<Comp> {
+        COMP_TABLE.by_bits(bits)
+    }
+
+    // shifts only exist on the extended Hack CPU
+    pub fn is_extended(&self) -> bool {
+        matches!(
+            self,
+            Comp::ShiftLeftD
+                | Comp::ShiftLeftA
+                | Comp::ShiftLeftM
+                | Comp::ShiftRightD
+                | Comp::ShiftRightA
+                | Comp::ShiftRightM
+        )
+    }
+
+    // true when the ALU reads RAM[A]
+    pub fn reads_m(&self) -> bool {
+        self.bits() & 0b1000000 != 0
+    }
+
+    // a bit followed by the six c bits
+    pub fn bits(&self) -> u16 {
+        self.entry().bits
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.entry().name
+    }
+
+    fn entry(&self) -> &'static Entry<Comp> {
+        let table = if self.is_extended() {
+            &EXTENDED_COMP_TABLE
+        } else {
+            &COMP_TABLE
+        };
+        table.entry(*self).expect("every comp has a table entry")
+    }
+}
+
+impl Dest {
+    pub fn from_bits(bits: u16) -> Option<Dest> {
+        DEST_TABLE.by_bits(bits)
+    }
+
+    pub fn writes_m(&self) -> bool {
+        self.bits() & 0b001 != 0
+    }
+
+    pub fn writes_a(&self) -> bool {
+        self.bits() & 0b100 != 0
+    }
+
+    pub fn bits(&self) -> u16 {
+        DEST_TABLE
+            .entry(*self)
+            .expect("every dest has a table entry")
+            .bits
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        DEST_TABLE
+            .entry(*self)
+            .expect("every dest has a table entry")
+            .name
+    }
+}
+
+impl Jump {
+    pub fn from_bits(bits: u16) -> Option<Jump> {
+        JUMP_TABLE.by_bits(bits)
+    }
+
+    pub fn bits(&self) -> u16 {
+        JUMP_TABLE
+            .entry(*self)
+            .expect("every jump has a table entry")
+            .bits
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        JUMP_TABLE
+            .entry(*self)
+            .expect("every jump has a table entry")
+            .name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_dest_spelling_is_accepted() {
+        for (dest, spellings) in [
+            (Dest::MD, &["MD", "DM"][..]),
+            (Dest::AM, &["AM", "MA"]),
+            (Dest::AD, &["AD", "DA"]),
+            (Dest::AMD, &["AMD", "ADM", "MAD", "MDA", "DAM", "DMA"]),
+        ] {
+            for spelling in spellings {
+                assert_eq!(handle_dest_instruct(spelling), Ok(dest), "{}", spelling);
+            }
+        }
+        assert!(handle_dest_instruct("AA").is_err());
+    }
+
+    #[test]
+    fn dest_bits_map_back_to_the_canonical_name() {
+        // no dest is written as nothing at all, not looked up
+        for bits in 1..8 {
+            let dest = Dest::from_bits(bits).unwrap();
+            assert_eq!(dest.bits(), bits);
+            assert_eq!(handle_dest_instruct(dest.mnemonic()), Ok(dest));
+        }
+    }
+}
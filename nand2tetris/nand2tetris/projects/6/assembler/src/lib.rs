@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+
+mod analysis;
+use crate::analysis::check_symbol_usage;
+
+pub mod disassembler;
+
+pub mod error;
+use crate::error::AsmError;
+use crate::error::Diagnostic;
+use crate::error::SpanError;
+
+pub mod instruction;
+use crate::instruction::Instruction;
+
+pub mod listing;
+
+mod macros;
+use crate::macros::expand;
+
+pub mod optimizer;
+
+pub mod output;
+
+mod parser;
+use crate::parser::MAX_CONSTANT;
+use crate::parser::Token;
+use crate::parser::TokenKind;
+use crate::parser::handle_c_instruction;
+use crate::parser::is_constant;
+use crate::parser::parse_a_instruction;
+use crate::parser::parse_c_instruction;
+use crate::parser::parse_constant;
+use crate::parser::parse_label;
+use crate::parser::qualify;
+use crate::parser::tokenize;
+
+pub mod stream;
+
+pub mod symbols;
+
+pub type SymbolTable = HashMap<String, u16>;
+
+// Words of Hack ROM
+pub const ROM_SIZE: usize = 32768;
+// Variables are allocated from 16 up to here, where the screen memory map starts
+pub const VARIABLE_END: u16 = 16384;
+
+// One input file, sources are assembled in order as if concatenated
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub path: String,
+    pub contents: String,
+}
+
+// A line after macro expansion, expanded lines keep the line number of the macro call
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    // index into Program.files
+    pub file: usize,
+    pub line_num: usize,
+    pub text: String,
+    pub expanded: bool,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub words: Vec<u16>,
+    pub instructions: Vec<Instruction>,
+    // where each instruction came from
+    pub source_lines: Vec<SourceLine>,
+    pub symbols: SymbolTable,
+    pub labels: SymbolTable,
+    pub variables: SymbolTable,
+    // from .equ
+    pub constants: SymbolTable,
+    // every source and included file, in the order they were read
+    pub files: Vec<String>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Switches for assemble_sources
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsmOptions {
+    // accept the extended ISA shifts D<<, A<<, M<<, D>>, A>> and M>>
+    pub extended: bool,
+}
+
+// Assembles a single source, `.include` paths are relative to the working directory
+pub fn assemble(contents: &str) -> Result<Program, AsmError> {
+    let source = SourceFile {
+        path: String::new(),
+        contents: contents.to_string(),
+    };
+    assemble_sources(&[source], AsmOptions::default())
+}
+
+// Runs both passes over the sources, every bad line is collected into the error.
+// Labels and variables are shared between files except `.local` ones
+pub fn assemble_sources(sources: &[SourceFile], options: AsmOptions) -> Result<Program, AsmError> {
+    let mut errors = AsmError::default();
+    let expansion = expand(sources, &mut errors);
+    errors.files = expansion.files.clone();
+    let tokens = tokenize(&expansion);
+    let mut symbol_table = init_symbol_table();
+    symbol_table.extend(expansion.constants.clone());
+    let mut labels = SymbolTable::new();
+    let mut variables = SymbolTable::new();
+    first_pass(
+        &tokens,
+        &mut symbol_table,
+        &mut labels,
+        &mut variables,
+        &mut errors,
+    );
+    let (instructions, source_lines) = parse_asm(&tokens, &symbol_table, options, &mut errors);
+
+    if errors.has_errors() {
+        return Err(errors);
+    }
+    check_symbol_usage(&tokens, &instructions, &labels, &variables, &mut errors);
+
+    let words = instructions.iter().map(|i| i.encode()).collect();
+    Ok(Program {
+        words,
+        instructions,
+        source_lines,
+        symbols: symbol_table,
+        labels,
+        variables,
+        constants: expansion.constants,
+        files: expansion.files,
+        warnings: errors.diagnostics,
+    })
+}
+
+impl Program {
+    // How much of ROM and the variable area the program uses, on one line
+    pub fn size_report(&self) -> String {
+        size_report(self.words.len(), self.labels.len(), &self.variables)
+    }
+}
+
+fn size_report(words: usize, labels: usize, variables: &SymbolTable) -> String {
+    let percent = words as f64 * 100.0 / ROM_SIZE as f64;
+    let rom = format!("{} of {} ROM words ({:.1}%)", words, ROM_SIZE, percent);
+    let lowest = variables.values().min();
+    let highest = variables.values().max();
+    let variables = match (lowest, highest) {
+        (Some(lowest), Some(highest)) => format!(
+            "variables at RAM {}-{} ({} of {})",
+            lowest,
+            highest,
+            variables.len(),
+            VARIABLE_END - 16
+        ),
+        _ => "no variables".to_string(),
+    };
+    format!("{}, {} labels, {}", rom, labels, variables)
+}
+
+pub fn init_symbol_table() -> SymbolTable {
+    let mut table: SymbolTable = HashMap::new();
+    table.insert("SCREEN".to_string(), 16384);
+    table.insert("KBD".to_string(), 24576);
+    table.insert("SP".to_string(), 0);
+    table.insert("LCL".to_string(), 1);
+    table.insert("ARG".to_string(), 2);
+    table.insert("THIS".to_string(), 3);
+    table.insert("THAT".to_string(), 4);
+
+    for i in 0..16 {
+        let r = format!("R{}", i);
+        table.insert(r, i);
+    }
+
+    table
+}
+
+fn first_pass(
+    tokens: &[Token],
+    symbol_table: &mut SymbolTable,
+    labels: &mut SymbolTable,
+    variables: &mut SymbolTable,
+    errors: &mut AsmError,
+) {
+    // handle (label)
+    let rom_words = tokens.iter().filter(|t| t.kind != TokenKind::Label).count();
+    let mut rom_addr: usize = 0;
+    let mut defined_on: HashMap<String, &Token> = HashMap::new();
+    for token in tokens {
+        if token.kind == TokenKind::Label {
+            let symbol = match parse_label(&token.text) {
+                Ok(symbol) => symbol,
+                Err(e) => {
+                    errors.push_token(token, e);
+                    continue;
+                }
+            };
+            let len = symbol.chars().count();
+            let symbol = qualify(&symbol, token.scope);
+            if symbol_table.contains_key(&symbol) && !labels.contains_key(&symbol) {
+                let kind = if init_symbol_table().contains_key(&symbol) {
+                    "predefined symbol"
+                } else {
+                    "`.equ` constant"
+                };
+                let e = SpanError::new(format!("label `{}` shadows the {}", symbol, kind), 1, len);
+                errors.push_token(token, e);
+            } else if let Some(first) = defined_on.get(&symbol) {
+                let place = if first.file == token.file {
+                    format!("line {}", first.line_num)
+                } else {
+                    format!("{}:{}", errors.files[first.file], first.line_num)
+                };
+                let e = SpanError::new(
+                    format!("label `{}` is already defined on {}", symbol, place),
+                    1,
+                    len,
+                );
+                errors.push_token(token, e);
+            } else if rom_addr > MAX_CONSTANT as usize && rom_words <= ROM_SIZE {
+                let e = SpanError::new(
+                    format!(
+                        "label `{}` is past the end of ROM, an A instruction can't load {}",
+                        symbol, rom_addr
+                    ),
+                    1,
+                    len,
+                );
+                errors.push_token(token, e);
+            } else {
+                defined_on.insert(symbol.clone(), token);
+                labels.insert(symbol.clone(), rom_addr as u16);
+                symbol_table.insert(symbol, rom_addr as u16);
+            }
+        } else {
+            if rom_addr == ROM_SIZE {
+                let e = SpanError::new(
+                    format!(
+                        "program needs {} words but the ROM only holds {}",
+                        rom_words, ROM_SIZE
+                    ),
+                    0,
+                    token.text.chars().count(),
+                );
+                errors.push_token(token, e);
+            }
+            rom_addr += 1;
+        }
+    }
+    // handle @label, malformed ones get reported by parse_asm
+    let mut symbol_num = 16;
+    for token in tokens {
+        if token.kind != TokenKind::AInstruction {
+            continue;
+        }
+        if let Ok(symbol) = parse_a_instruction(&token.text)
+            && let symbol = qualify(&symbol, token.scope)
+            && !is_constant(&symbol)
+            && !symbol_table.contains_key(&symbol)
+        {
+            if symbol_num == VARIABLE_END {
+                let e = SpanError::new(
+                    format!(
+                        "variable `{}` would be allocated at RAM {}, inside the screen memory map, only {} variables fit",
+                        symbol,
+                        VARIABLE_END,
+                        VARIABLE_END - 16
+                    ),
+                    1,
+                    token.text.chars().count() - 1,
+                );
+                errors.push_token(token, e);
+            }
+            variables.insert(symbol.clone(), symbol_num);
+            symbol_table.insert(symbol, symbol_num);
+            symbol_num = symbol_num.saturating_add(1);
+        }
+    }
+}
+
+fn get_address(symbol: &str, symbol_table: &SymbolTable) -> Result<Instruction, SpanError> {
+    if let Some(symbol_addr) = symbol_table.get(symbol) {
+        Ok(Instruction::A {
+            value: *symbol_addr,
+            symbol: Some(symbol.to_string()),
+        })
+    } else {
+        // otherwise it's num/hardcoded
+        match parse_constant(symbol) {
+            Ok(value) => Ok(Instruction::A {
+                value,
+                symbol: None,
+            }),
+            Err(e) => Err(SpanError::new(e, 1, symbol.chars().count())),
+        }
+    }
+}
+
+fn parse_line(
+    token: &Token,
+    symbol_table: &SymbolTable,
+    options: AsmOptions,
+) -> Result<Instruction, SpanError> {
+    if token.kind == TokenKind::AInstruction {
+        let symbol = parse_a_instruction(&token.text)?;
+        get_address(&qualify(&symbol, token.scope), symbol_table)
+    } else {
+        let c_instruct = parse_c_instruction(&token.text)?;
+        handle_c_instruction(&c_instruct, options.extended)
+    }
+}
+
+fn parse_asm(
+    tokens: &[Token],
+    symbol_table: &SymbolTable,
+    options: AsmOptions,
+    errors: &mut AsmError,
+) -> (Vec<Instruction>, Vec<SourceLine>) {
+    let mut instructions = Vec::new();
+    let mut source_lines = Vec::new();
+    for token in tokens {
+        if token.kind == TokenKind::Label {
+            continue;
+        }
+
+        match parse_line(token, symbol_table, options) {
+            Ok(instruction) => {
+                instructions.push(instruction);
+                source_lines.push(SourceLine {
+                    file: token.file,
+                    line_num: token.line_num,
+                    text: token.source.to_string(),
+                    expanded: token.expanded,
+                });
+            }
+            Err(e) => errors.push_token(token, e),
+        }
+    }
+
+    (instructions, source_lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    use crate::disassembler::disassemble;
+    use crate::disassembler::read_hack;
+    use crate::symbols::SymbolMap;
+
+    fn read(path: &str) -> String {
+        fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join(path)).unwrap()
+    }
+
+    #[test]
+    fn programs_match_the_reference_hack_files() {
+        for (asm, hack) in [
+            ("../add/Add.asm", "../../5/Add.hack"),
+            ("../max/Max.asm", "../../5/Max.hack"),
+            ("../rect/Rect.asm", "../../5/Rect.hack"),
+        ] {
+            let program = assemble(&read(asm)).unwrap();
+            assert_eq!(program.words, read_hack(&read(hack)).unwrap(), "{}", asm);
+        }
+    }
+
+    // no .hack comes with Pong, its symbol-less PongL.asm is the reference
+    #[test]
+    fn symbols_resolve_like_the_plain_versions() {
+        for (asm, plain) in [
+            ("../max/Max.asm", "../max/MaxL.asm"),
+            ("../rect/Rect.asm", "../rect/RectL.asm"),
+            ("../pong/Pong.asm", "../pong/PongL.asm"),
+        ] {
+            let program = assemble(&read(asm)).unwrap();
+            let expected = assemble(&read(plain)).unwrap();
+            assert!(
+                program.words == expected.words,
+                "{} differs from {}",
+                asm,
+                plain
+            );
+        }
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_words() {
+        for asm in [
+            "../add/Add.asm",
+            "../max/Max.asm",
+            "../rect/Rect.asm",
+            "../pong/Pong.asm",
+        ] {
+            let program = assemble(&read(asm)).unwrap();
+            let symbols = SymbolMap::from_program(&program);
+            for symbols in [None, Some(&symbols)] {
+                let disassembly = disassemble(&program.words, symbols);
+                assert!(disassembly.undecodable.is_empty(), "{}", asm);
+                let again = assemble(&disassembly.asm).unwrap();
+                assert!(again.words == program.words, "{} changed", asm);
+            }
+        }
+    }
+
+    fn errors(source: &str) -> Vec<(usize, usize, String, String)> {
+        let mut diagnostics = assemble(source).unwrap_err().diagnostics;
+        diagnostics.sort_by_key(|d| (d.line, d.col));
+        diagnostics
+            .into_iter()
+            .map(|d| (d.line, d.col, d.text, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn every_bad_line_is_reported_at_its_column() {
+        let source = "D=A\n  AM=D+Q  // bad comp\n0;JMPX\n@12a\n";
+        let errors = errors(source);
+        let spans: Vec<(usize, usize, &str)> = errors
+            .iter()
+            .map(|(line, col, text, _)| (*line, *col, text.as_str()))
+            .collect();
+        assert_eq!(spans, [(2, 6, "D+Q"), (3, 3, "JMPX"), (4, 2, "12a")]);
+
+        let rendered = assemble(source).unwrap_err().to_string();
+        assert!(rendered.contains("2 |   AM=D+Q  // bad comp\n  |      ^^^\n"));
+        assert!(rendered.ends_with("error: aborting due to 3 previous errors\n"));
+    }
+
+    #[test]
+    fn undecodable_words_become_comments() {
+        // a=1 with the comp bits of `1`, which has no M form
+        let words = [0b0000_0000_0000_0101, 0xFFFF, 0b1110_1100_0001_0000];
+        assert_eq!(Instruction::decode(0xFFFF), None);
+        let disassembly = disassemble(&words, None);
+        assert_eq!(disassembly.undecodable, [1]);
+        assert_eq!(
+            disassembly.asm,
+            "@5\n// undecodable word 1111111111111111 at 1\nD=A\n"
+        );
+        assert_eq!(
+            read_hack("0000000000000101\n01x1\n")
+                .unwrap_err()
+                .diagnostics[0]
+                .message,
+            "`01x1` is not a 16 bit binary word"
+        );
+    }
+
+    #[test]
+    fn labels_must_be_new_names() {
+        let source = ".equ LIMIT 10\n(LOOP)\n@LOOP\n(LOOP)\n0;JMP\n(SP)\n( LIMIT )\n";
+        let errors = errors(source);
+        let found: Vec<(usize, usize, &str, &str)> = errors
+            .iter()
+            .map(|(line, col, text, message)| (*line, *col, text.as_str(), message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (4, 2, "LOOP", "label `LOOP` is already defined on line 2"),
+                (6, 2, "SP", "label `SP` shadows the predefined symbol"),
+                (7, 3, "LIMIT", "label `LIMIT` shadows the `.equ` constant"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rom_overflow_is_an_error() {
+        let full = "D=A\n".repeat(ROM_SIZE);
+        assert_eq!(assemble(&full).unwrap().words.len(), ROM_SIZE);
+
+        let over = format!("{}D=A\n", full);
+        let too_long = errors(&over);
+        assert_eq!(too_long.len(), 1);
+        assert_eq!(
+            (too_long[0].0, too_long[0].3.as_str()),
+            (
+                ROM_SIZE + 1,
+                "program needs 32769 words but the ROM only holds 32768"
+            )
+        );
+
+        let past_end = errors(&format!("{}(END)\n", full));
+        assert_eq!(
+            past_end[0].3,
+            "label `END` is past the end of ROM, an A instruction can't load 32768"
+        );
+    }
+
+    #[test]
+    fn variables_stop_at_the_screen() {
+        let count = (VARIABLE_END - 16) as usize;
+        let fits: String = (0..count).map(|i| format!("@v{}\n", i)).collect();
+        let program = assemble(&fits).unwrap();
+        assert_eq!(
+            program.variables[&format!("v{}", count - 1)],
+            VARIABLE_END - 1
+        );
+
+        let errors = errors(&format!("{}@v0\n@one_more\n", fits));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            (errors[0].0, errors[0].2.as_str(), errors[0].3.as_str()),
+            (
+                count + 2,
+                "one_more",
+                "variable `one_more` would be allocated at RAM 16384, inside the screen memory map, only 16368 variables fit"
+            )
+        );
+    }
+}
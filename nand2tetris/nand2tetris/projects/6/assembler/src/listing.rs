@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::Program;
+use crate::SymbolTable;
+
+// ROM address | word in binary and hex | file:line and source, then the
+// symbol table. Lines that came out of a macro are marked with a + after the
+// line number
+pub fn listing(program: &Program) -> String {
+    let mut labels_at: HashMap<u16, Vec<&String>> = HashMap::new();
+    for (name, addr) in &program.labels {
+        labels_at.entry(*addr).or_default().push(name);
+    }
+    for names in labels_at.values_mut() {
+        names.sort();
+    }
+
+    let places: Vec<String> = program
+        .source_lines
+        .iter()
+        .map(|source| place(&program.files[source.file], source.line_num))
+        .collect();
+    let width = places.iter().map(|p| p.len()).max().unwrap_or(0).max(4);
+
+    let mut lst = String::new();
+    lst.push_str(&format!(
+        "  ROM  binary            hex     {:>width$}  source\n",
+        "line"
+    ));
+    for (i, word) in program.words.iter().enumerate() {
+        let addr = i as u16;
+        push_labels(&mut lst, labels_at.get(&addr), width);
+        let source = &program.source_lines[i];
+        let marker = if source.expanded { '+' } else { ' ' };
+        lst.push_str(&format!(
+            "{:5}  {:016b}  0x{:04X}  {:>width$}{} {}\n",
+            addr,
+            word,
+            word,
+            places[i],
+            marker,
+            source.text.trim()
+        ));
+    }
+    // labels like (END) can sit right after the last instruction
+    push_labels(
+        &mut lst,
+        labels_at.get(&(program.words.len() as u16)),
+        width,
+    );
+
+    lst.push_str("\nLabels (ROM)\n");
+    push_symbols(&mut lst, &program.labels);
+    lst.push_str("\nVariables (RAM)\n");
+    push_symbols(&mut lst, &program.variables);
+    if !program.constants.is_empty() {
+        lst.push_str("\nConstants (.equ)\n");
+        push_symbols(&mut lst, &program.constants);
+    }
+    lst
+}
+
+// `Mult.asm:12`, or just the line when the source didn't come from a file
+fn place(path: &str, line_num: usize) -> String {
+    match Path::new(path).file_name() {
+        Some(name) => format!("{}:{}", name.to_string_lossy(), line_num),
+        None => line_num.to_string(),
+    }
+}
+
+fn push_labels(lst: &mut String, names: Option<&Vec<&String>>, width: usize) {
+    for name in names.into_iter().flatten() {
+        lst.push_str(&format!("{:w$}({})\n", "", name, w = 35 + width));
+    }
+}
+
+fn push_symbols(lst: &mut String, table: &SymbolTable) {
+    let mut symbols: Vec<(&u16, &String)> = table.iter().map(|(name, addr)| (addr, name)).collect();
+    symbols.sort();
+    for (addr, name) in symbols {
+        lst.push_str(&format!("{:5}  {}\n", addr, name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assemble;
+
+    #[test]
+    fn lines_without_a_file_show_just_the_line() {
+        let lst = listing(&assemble("@2\n(END)\n@END\n0;JMP\n").unwrap());
+        assert!(
+            lst.contains("    0  0000000000000010  0x0002     1  @2\n"),
+            "{}",
+            lst
+        );
+        assert!(lst.contains("(END)\n    1"), "{}", lst);
+    }
+
+    #[test]
+    fn places_name_the_file() {
+        assert_eq!(place("projects/6/max/Max.asm", 12), "Max.asm:12");
+        assert_eq!(place("", 12), "12");
+    }
+}
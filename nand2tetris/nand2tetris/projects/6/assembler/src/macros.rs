@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::SourceFile;
+use crate::SourceLine;
+use crate::SymbolTable;
+use crate::error::AsmError;
+use crate::error::SpanError;
+use crate::init_symbol_table;
+use crate::parser::is_identifier;
+use crate::parser::parse_constant;
+use crate::parser::qualify;
+
+// Macros may call other macros and files include other files,
+// this stops runaway recursion
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug)]
+struct Macro {
+    // highest %n used in the body
+    params: usize,
+    body: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Expansion {
+    pub lines: Vec<SourceLine>,
+    pub constants: SymbolTable,
+    // path of every file read, SourceLine.file indexes this
+    pub files: Vec<String>,
+    // prefix given to the `.local` labels of each file
+    pub scopes: Vec<String>,
+}
+
+struct Expander<'e> {
+    macros: HashMap<String, Macro>,
+    // bumped on every expansion so %%locals are unique
+    count: usize,
+    expansion: Expansion,
+    // files currently being read, to catch include cycles
+    including: Vec<PathBuf>,
+    predefined: SymbolTable,
+    // file and line each constant was defined on
+    defined_on: HashMap<String, (usize, usize)>,
+    errors: &'e mut AsmError,
+}
+
+// file, line number and text of the line being expanded
+#[derive(Debug, Clone, Copy)]
+struct Site<'a> {
+    file: usize,
+    line_num: usize,
+    line: &'a str,
+}
+
+// Reads every source in order, pulling in `.include "file.asm"` relative to
+// the including file, expanding `.macro NAME` ... `.endm` blocks and
+// collecting `.equ NAME value`. Inside a macro body %1..%9 are the call's comma
+// separated arguments and %%name becomes NAME$<n>.name, unique to that expansion.
+pub fn expand(sources: &[SourceFile], errors: &mut AsmError) -> Expansion {
+    let mut expander = Expander {
+        macros: HashMap::new(),
+        count: 0,
+        expansion: Expansion::default(),
+        including: Vec::new(),
+        predefined: init_symbol_table(),
+        defined_on: HashMap::new(),
+        errors,
+    };
+    for source in sources {
+        expander.file(&source.path, &source.contents);
+    }
+    expander.expansion
+}
+
+impl Expander<'_> {
+    fn file(&mut self, path: &str, contents: &str) {
+        let file = self.expansion.files.len();
+        self.expansion.files.push(path.to_string());
+        self.expansion.scopes.push(scope_name(path, file));
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+        self.including.push(canonical);
+
+        // name, line it started on and body collected so far
+        let mut defining: Option<(String, usize, Vec<String>)> = None;
+        for (idx, line) in contents.lines().enumerate() {
+            let site = Site {
+                file,
+                line_num: idx + 1,
+                line,
+            };
+            let code = strip_comment(line).trim();
+            let directive = code.split_whitespace().next().unwrap_or("");
+
+            if let Some((name, start, mut body)) = defining.take() {
+                match directive {
+                    ".endm" => {
+                        let params = highest_param(&body);
+                        self.macros.insert(name, Macro { params, body });
+                    }
+                    ".macro" => {
+                        self.error(site, "macros can't be defined inside a macro");
+                        defining = Some((name, start, body));
+                    }
+                    _ => {
+                        body.push(line.to_string());
+                        defining = Some((name, start, body));
+                    }
+                }
+                continue;
+            }
+
+            match directive {
+                ".macro" => {
+                    let name = code.split_whitespace().nth(1).unwrap_or("");
+                    if !is_identifier(name) {
+                        self.error(site, "expected a macro name after `.macro`");
+                    } else if self.macros.contains_key(name) {
+                        self.error(site, &format!("macro `{}` is already defined", name));
+                    } else {
+                        defining = Some((name.to_string(), site.line_num, Vec::new()));
+                    }
+                }
+                ".endm" => self.error(site, "`.endm` without a matching `.macro`"),
+                ".equ" => self.equ(site, code),
+                ".include" => self.include(site, path, code),
+                name if name.starts_with('.') => {
+                    self.error(site, &format!("unknown directive `{}`", name));
+                }
+                name if self.macros.contains_key(name) => {
+                    let args = parse_args(code, name);
+                    self.call(name, &args, site, 0);
+                }
+                _ => self.expansion.lines.push(SourceLine {
+                    file,
+                    line_num: site.line_num,
+                    text: line.to_string(),
+                    expanded: false,
+                }),
+            }
+        }
+
+        if let Some((name, start, _)) = defining {
+            let site = Site {
+                file,
+                line_num: start,
+                line: contents.lines().nth(start - 1).unwrap_or(""),
+            };
+            self.error(site, &format!("macro `{}` is missing its `.endm`", name));
+        }
+        self.including.pop();
+    }
+
+    fn equ(&mut self, site: Site, code: &str) {
+        let fields: Vec<&str> = code.split_whitespace().collect();
+        let value = fields.get(2).map(|v| parse_constant(v));
+        match (fields.get(1), value) {
+            (Some(name), Some(Ok(value))) if fields.len() == 3 && is_identifier(name) => {
+                // `.FOO` is private to its file like a label, @.FOO is looked
+                // up by the same qualified name
+                let name = qualify(name, &self.expansion.scopes[site.file]);
+                if self.predefined.contains_key(&name) {
+                    let message = format!("`.equ` can't redefine the predefined symbol `{}`", name);
+                    self.error(site, &message);
+                } else if let Some((file, line_num)) = self.defined_on.get(&name).copied() {
+                    let place = if file == site.file {
+                        format!("line {}", line_num)
+                    } else {
+                        format!("{}:{}", self.expansion.files[file], line_num)
+                    };
+                    let message = format!("constant `{}` is already defined on {}", name, place);
+                    self.error(site, &message);
+                } else {
+                    self.defined_on
+                        .insert(name.clone(), (site.file, site.line_num));
+                    self.expansion.constants.insert(name, value);
+                }
+            }
+            (_, Some(Err(e))) => self.error(site, &e),
+            _ => self.error(site, "expected `.equ NAME value`"),
+        }
+    }
+
+    // The included file is read in place of the directive
+    fn include(&mut self, site: Site, path: &str, code: &str) {
+        let target = code[".include".len()..].trim();
+        let Some(target) = target
+            .strip_prefix('"')
+            .and_then(|t| t.strip_suffix('"'))
+            .filter(|t| !t.is_empty())
+        else {
+            self.error(site, "expected `.include \"file.asm\"`");
+            return;
+        };
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        let resolved = dir.join(target);
+        let canonical = fs::canonicalize(&resolved).unwrap_or_else(|_| resolved.clone());
+        if self.including.contains(&canonical) {
+            self.error(site, &format!("`{}` includes itself", target));
+            return;
+        }
+        if self.including.len() >= MAX_DEPTH {
+            let message = format!("includes are nested more than {} levels deep", MAX_DEPTH);
+            self.error(site, &message);
+            return;
+        }
+        match fs::read_to_string(&resolved) {
+            Ok(contents) => self.file(&resolved.to_string_lossy(), &contents),
+            Err(e) => self.error(site, &format!("can't read `{}`: {}", target, e)),
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[String], site: Site, depth: usize) {
+        if depth >= MAX_DEPTH {
+            let message = format!(
+                "macro `{}` expands more than {} levels deep",
+                name, MAX_DEPTH
+            );
+            self.error(site, &message);
+            return;
+        }
+        let params = self.macros[name].params;
+        if args.len() != params {
+            let message = format!(
+                "macro `{}` takes {} argument{} but {} were given",
+                name,
+                params,
+                if params == 1 { "" } else { "s" },
+                args.len()
+            );
+            self.error(site, &message);
+            return;
+        }
+
+        self.count += 1;
+        let unique = format!("{}${}", name, self.count);
+        let body = self.macros[name].body.clone();
+        for body_line in body {
+            let text = substitute(&body_line, args, &unique);
+            let code = strip_comment(&text).trim();
+            let inner = code.split_whitespace().next().unwrap_or("");
+            if self.macros.contains_key(inner) {
+                let inner_args = parse_args(code, inner);
+                self.call(inner, &inner_args, site, depth + 1);
+            } else {
+                self.expansion.lines.push(SourceLine {
+                    file: site.file,
+                    line_num: site.line_num,
+                    text,
+                    expanded: true,
+                });
+            }
+        }
+    }
+
+    fn error(&mut self, site: Site, message: &str) {
+        let line = site.line;
+        let start = line.len() - line.trim_start().len();
+        let len = strip_comment(line).trim().chars().count();
+        let cols: Vec<usize> = (start..start + len.max(1)).collect();
+        let err = SpanError::new(message.to_string(), 0, cols.len());
+        self.errors
+            .push_in(site.file, site.line_num, line, &cols, err);
+    }
+}
+
+// File stem made into a symbol plus the file's index, e.g. Mult$2
+pub fn scope_name(path: &str, file: usize) -> String {
+    let stem = Path::new(path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut name: String = stem
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .collect();
+    if !is_identifier(&name) {
+        name.insert_str(0, "file");
+    }
+    format!("{}${}", name, file)
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find("//") {
+        Some(at) => &line[..at],
+        None => line,
+    }
+}
+
+fn parse_args(code: &str, name: &str) -> Vec<String> {
+    let rest = code[name.len()..].trim();
+    if rest.is_empty() {
+        return Vec::new();
+    }
+    rest.split(',').map(|arg| arg.trim().to_string()).collect()
+}
+
+fn highest_param(body: &[String]) -> usize {
+    let mut highest = 0;
+    for line in body {
+        let chars: Vec<char> = line.chars().collect();
+        for (i, ch) in chars.iter().enumerate() {
+            if *ch == '%'
+                && (i == 0 || chars[i - 1] != '%')
+                && let Some(n) = chars.get(i + 1).and_then(|c| c.to_digit(10))
+            {
+                highest = highest.max(n as usize);
+            }
+        }
+    }
+    highest
+}
+
+// Replaces %n with arguments and %%name with a per expansion label
+fn substitute(line: &str, args: &[String], unique: &str) -> String {
+    let mut text = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            text.push(ch);
+            continue;
+        }
+        match chars.peek() {
+            Some('%') => {
+                chars.next();
+                text.push_str(unique);
+                text.push('.');
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let n = c.to_digit(10).unwrap_or(0) as usize;
+                chars.next();
+                if let Some(arg) = n.checked_sub(1).and_then(|i| args.get(i)) {
+                    text.push_str(arg);
+                }
+            }
+            _ => text.push(ch),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assemble;
+    use crate::instruction::Instruction;
+
+    fn a_values(source: &str) -> Vec<u16> {
+        assemble(source)
+            .unwrap()
+            .instructions
+            .iter()
+            .filter_map(|i| match i {
+                Instruction::A { value, .. } => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn file_local_constants_resolve() {
+        let program = assemble(".equ .FOO 3\n@.FOO\nD=A\n").unwrap();
+        assert!(program.variables.is_empty());
+        assert_eq!(a_values(".equ .FOO 3\n@.FOO\nD=A\n"), vec![3]);
+    }
+
+    #[test]
+    fn constants_can_only_be_defined_once() {
+        let errors = assemble(".equ FOO 3\n.equ FOO 4\n@FOO\n").unwrap_err();
+        let text = errors.to_string();
+        assert!(
+            text.contains("constant `FOO` is already defined on line 1"),
+            "{}",
+            text
+        );
+    }
+
+    #[test]
+    fn macros_expand_with_arguments_and_unique_labels() {
+        let source = "\
+.macro PUSH
+@%1
+D=A
+(%%here)
+@%%here
+.endm
+PUSH 7
+PUSH 9
+";
+        assert_eq!(a_values(source), vec![7, 2, 9, 5]);
+    }
+}
@@ -1,254 +1,236 @@
-use std::collections::HashMap;
 use std::env;
 use std::fs;
-
-const PLATFORM_BYTES: usize = 16;
-
-#[derive(Debug)]
-struct CInstruction {
-    comp: String,
-    dest: Option<String>,
-    jmp: Option<String>,
-}
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+use assembler::AsmOptions;
+use assembler::SourceFile;
+use assembler::assemble_sources;
+use assembler::disassembler::disassemble;
+use assembler::disassembler::read_hack;
+use assembler::error::render;
+use assembler::listing::listing;
+use assembler::optimizer::optimize;
+use assembler::output::OutputFormat;
+use assembler::stream::assemble_stream;
+use assembler::symbols::SymbolMap;
+
+const USAGE: &str = "\
+usage: assembler <file.asm>... [--format FORMATS] [--listing] [--sym] [--sym-json]
+                 [--optimize] [--stream] [--extended]
+       assembler disassemble <file.hack> [--symbols [file.sym]]
+FORMATS is a comma separated list of hack, bin, bin-le, ihex, logisim, memb, memh
+--stream assembles one file in a single pass, writing the words as they are
+resolved. It takes one of hack, bin or bin-le and doesn't support macros,
+.include or the other outputs.
+";
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
-    let file_name = match file_path.split("/").last() {
-        Some(fname) => fname.replace(".asm", ".hack"),
-        None => "no_name.hack".to_string(),
-    };
-
-    let contents = fs::read_to_string(file_path).expect("Can't read file!");
-    let mut symbol_table = init_symbol_table();
-    first_pass(&contents, &mut symbol_table);
-    let hack_file: String = parse_asm(&contents, &symbol_table);
-
-    fs::write(format!("./{}", &file_name), hack_file).expect("Can't write file!");
-}
-
-fn init_symbol_table() -> HashMap<String, u32> {
-    let mut table: HashMap<String, u32> = HashMap::new();
-    table.insert("SCREEN".to_string(), 16384);
-    table.insert("KBD".to_string(), 24576);
-    table.insert("SP".to_string(), 0);
-    table.insert("LCL".to_string(), 1);
-    table.insert("ARG".to_string(), 2);
-    table.insert("THIS".to_string(), 3);
-    table.insert("THAT".to_string(), 4);
-
-    for i in 0..16 {
-        let r = format!("R{}", i.to_string());
-        table.insert(r, i);
+    if args.len() < 2 {
+        eprint!("{}", USAGE);
+        process::exit(1);
     }
-
-    return table;
-}
-
-fn remove_all_whitespace(s: &str) -> String {
-    return s.split_whitespace().collect();
-}
-
-fn parse_a_instruction(instruction: &str) -> String {
-    let split: Vec<&str> = instruction.split("@").collect();
-    if split.len() == 2 {
-        return split[1].to_string();
-    } else {
-        panic!("Error parsing A instruction");
+    if args[1] == "disassemble" {
+        run_disassemble(&args[2..]);
+        return;
     }
-}
 
-fn parse_c_instruction(instruction: &str) -> CInstruction {
-    let mut c_instruct = CInstruction {
-        comp: String::new(),
-        dest: None,
-        jmp: None,
+    // every other argument is a source, the outputs are named after the first
+    let format_at = args.iter().position(|arg| arg == "--format");
+    let inputs: Vec<&String> = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(i, arg)| !arg.starts_with("--") && format_at.map(|at| at + 1) != Some(*i))
+        .map(|(_, arg)| arg)
+        .collect();
+    let Some(file_path) = inputs.first().copied() else {
+        eprint!("error: expected a .asm file to assemble\n{}", USAGE);
+        process::exit(1);
+    };
+    let write_listing = args.iter().any(|arg| arg == "--listing");
+    let write_sym = args.iter().any(|arg| arg == "--sym");
+    let write_sym_json = args.iter().any(|arg| arg == "--sym-json");
+    let run_optimizer = args.iter().any(|arg| arg == "--optimize");
+    let stream = args.iter().any(|arg| arg == "--stream");
+    let options = AsmOptions {
+        extended: args.iter().any(|arg| arg == "--extended"),
+    };
+    // --format hack,bin,bin-le,ihex,logisim,memb,memh
+    let formats: Vec<OutputFormat> = match format_at {
+        Some(at) => {
+            let Some(names) = args.get(at + 1) else {
+                eprint!("error: expected formats after --format\n{}", USAGE);
+                process::exit(1);
+            };
+            names
+                .split(',')
+                .map(|name| {
+                    OutputFormat::parse(name).unwrap_or_else(|| {
+                        eprintln!("error: unknown output format `{}`", name);
+                        process::exit(1);
+                    })
+                })
+                .collect()
+        }
+        None => vec![OutputFormat::Hack],
     };
 
-    let split_jmp: Vec<&str> = instruction.split(";").collect();
-    if split_jmp.len() == 2 {
-        c_instruct.jmp = Some(split_jmp[1].to_string());
-    } else if split_jmp.len() > 2 {
-        panic!("Error parsing C instruction!");
+    // single pass straight from the file, for huge generated programs
+    if stream {
+        if inputs.len() > 1 || write_listing || write_sym || write_sym_json || run_optimizer {
+            eprintln!(
+                "error: --stream takes one file and can't be combined with --listing, --sym, --sym-json or --optimize"
+            );
+            process::exit(1);
+        }
+        let [format] = formats[..] else {
+            eprintln!("error: --stream writes one output format");
+            process::exit(1);
+        };
+        if !format.streams() {
+            eprintln!("error: --stream can only write hack, bin or bin-le");
+            process::exit(1);
+        }
+        let input = File::open(file_path).expect("Can't read file!");
+        let out_name = output_name(file_path, format.extension());
+        let out = BufWriter::new(File::create(&out_name).expect("Can't write file!"));
+        let streamed = match assemble_stream(BufReader::new(input), format, out, file_path, options)
+        {
+            Ok(streamed) => streamed,
+            Err(errors) => {
+                // don't leave half a program behind
+                let _ = fs::remove_file(&out_name);
+                eprint!("{}", errors);
+                process::exit(1);
+            }
+        };
+        let files = [file_path.to_string()];
+        eprint!("{}", render(&files, &streamed.warnings));
+        println!("{}: {}", file_path, streamed.size_report());
+        return;
     }
 
-    let split_dest: Vec<&str> = split_jmp[0].split("=").collect();
-
-    if split_dest.len() == 1 {
-        c_instruct.comp = split_dest[0].to_string();
-    } else if split_dest.len() == 2 {
-        c_instruct.dest = Some(split_dest[0].to_string());
-        c_instruct.comp = split_dest[1].to_string();
-    } else {
-        panic!("Error parsing C instruction!");
-    }
+    let sources: Vec<SourceFile> = inputs
+        .iter()
+        .map(|path| SourceFile {
+            path: path.to_string(),
+            contents: fs::read_to_string(path).expect("Can't read file!"),
+        })
+        .collect();
+    let mut program = match assemble_sources(&sources, options) {
+        Ok(program) => program,
+        Err(errors) => {
+            eprint!("{}", errors);
+            process::exit(1);
+        }
+    };
 
-    return c_instruct;
-}
+    eprint!("{}", render(&program.files, &program.warnings));
 
-fn first_pass(contents: &str, symbol_table: &mut HashMap<String, u32>) {
-    // handle (label)
-    let mut line_num = 0;
-    for line in contents.lines() {
-        let instruction = remove_all_whitespace(line);
-        if line.is_empty() || instruction.starts_with('/') {
-            continue;
-        } else if instruction.starts_with("(") {
-            let symbol = instruction.replace(&['(', ')'], "");
-            symbol_table.insert(symbol, line_num);
-        } else {
-            line_num += 1;
+    if run_optimizer {
+        match optimize(&mut program) {
+            Ok(stats) => println!(
+                "optimized {}: {} -> {} instructions",
+                file_path, stats.before, stats.after
+            ),
+            Err(e) => eprintln!("warning: not optimizing {}: {}", file_path, e),
         }
     }
-    // handle @label
-    let mut symbol_num = 16;
-    for line in contents.lines() {
-        let instruction = remove_all_whitespace(line);
-        if !line.is_empty() && instruction.starts_with('@') {
-            let symbol = parse_a_instruction(&instruction);
-            if !symbol.starts_with(|c: char| c.is_numeric()) {
-                if !symbol_table.contains_key(&symbol) {
-                    symbol_table.insert(symbol, symbol_num);
-                    symbol_num += 1;
-                }
-            }
-        }
+    println!("{}: {}", file_path, program.size_report());
+
+    write_outputs(&formats, file_path, &program.words);
+
+    if write_listing {
+        let lst = listing(&program);
+        fs::write(output_name(file_path, "lst"), lst).expect("Can't write file!");
     }
-}
 
-fn get_address(symbol: &str, symbol_table: &HashMap<String, u32>) -> u32 {
-    if let Some(symbol_addr) = symbol_table.get(symbol) {
-        return symbol_addr.clone();
-    } else {
-        // otherwise it's num/hardcoded
-        match symbol.parse::<u32>() {
-            Ok(addr) => {
-                return addr;
-            }
-            Err(e) => {
-                panic!("{}", e);
-            }
+    if write_sym || write_sym_json {
+        let symbols = SymbolMap::from_program(&program);
+        if write_sym {
+            let sym_name = output_name(file_path, "sym");
+            fs::write(sym_name, symbols.to_text()).expect("Can't write file!");
+        }
+        if write_sym_json {
+            let json_name = output_name(file_path, "sym.json");
+            fs::write(json_name, symbols.to_json()).expect("Can't write file!");
         }
     }
 }
 
-fn handle_comp_instruct(comp: &str) -> [char; 6] {
-    return match comp {
-        "0" => ['1', '0', '1', '0', '1', '0'],
-        "1" => ['1', '1', '1', '1', '1', '1'],
-        "-1" => ['1', '1', '1', '0', '1', '0'],
-        "D" => ['0', '0', '1', '1', '0', '0'],
-        "A" | "M" => ['1', '1', '0', '0', '0', '0'],
-        "!D" => ['0', '0', '1', '1', '0', '1'],
-        "!A" | "!M" => ['1', '1', '0', '0', '0', '1'],
-        "-D" => ['0', '0', '1', '1', '1', '1'],
-        "-A" | "-M" => ['1', '1', '0', '0', '1', '1'],
-        "D+1" => ['0', '1', '1', '1', '1', '1'],
-        "A+1" | "M+1" => ['1', '1', '0', '1', '1', '1'],
-        "D-1" => ['0', '0', '1', '1', '1', '0'],
-        "A-1" | "M-1" => ['1', '1', '0', '0', '1', '0'],
-        "D+A" | "D+M" => ['0', '0', '0', '0', '1', '0'],
-        "D-A" | "D-M" => ['0', '1', '0', '0', '1', '1'],
-        "A-D" | "M-D" => ['0', '0', '0', '1', '1', '1'],
-        "D&A" | "D&M" => ['0', '0', '0', '0', '0', '0'],
-        "D|A" | "D|M" => ['0', '1', '0', '1', '0', '1'],
-        _ => panic!("Error matching comp instruction"),
-    };
-}
-fn handle_dest_instruct(dest: &str) -> [char; 3] {
-    return match dest {
-        "M" => ['0', '0', '1'],
-        "D" => ['0', '1', '0'],
-        "DM" | "MD" => ['0', '1', '1'],
-        "A" => ['1', '0', '0'],
-        "AM" | "MA" => ['1', '0', '1'],
-        "AD" | "DA" => ['1', '1', '0'],
-        "ADM" => ['1', '1', '1'],
-        _ => panic!("Error matching dest instruction"),
-    };
+// `./<stem>.<extension>` in the working directory for an input file, so
+// `src/prog.asm` and `prog.s` both give `./prog.hack`
+fn output_name(input: &str, extension: &str) -> String {
+    let stem = Path::new(input)
+        .file_stem()
+        .map_or("no_name".into(), |stem| stem.to_string_lossy());
+    format!("./{}.{}", stem, extension)
 }
 
-fn handle_jmp_instruct(jmp: &str) -> [char; 3] {
-    return match jmp {
-        "JGT" => ['0', '0', '1'],
-        "JEQ" => ['0', '1', '0'],
-        "JGE" => ['0', '1', '1'],
-        "JLT" => ['1', '0', '0'],
-        "JNE" => ['1', '0', '1'],
-        "JLE" => ['1', '1', '0'],
-        "JMP" => ['1', '1', '1'],
-        _ => panic!("Error matching jmp instruction"),
-    };
+fn write_outputs(formats: &[OutputFormat], input: &str, words: &[u16]) {
+    for format in formats {
+        let file = File::create(output_name(input, format.extension())).expect("Can't write file!");
+        let mut out = BufWriter::new(file);
+        format
+            .write(words, &mut out)
+            .and_then(|_| out.flush())
+            .expect("Can't write file!");
+    }
 }
-fn handle_c_instruction(instruct: &CInstruction) -> [char; PLATFORM_BYTES] {
-    let placeholder = ['1'; 3];
-    let a = if instruct.comp.contains("M") {
-        ['1']
-    } else {
-        ['0']
-    };
 
-    let c = handle_comp_instruct(&instruct.comp);
-    let j = match &instruct.jmp {
-        Some(s) => handle_jmp_instruct(&s),
-        None => ['0'; 3],
+// disassemble <file.hack> [--symbols [file.sym]], writes ./<name>.dis.asm
+fn run_disassemble(args: &[String]) {
+    let Some(file_path) = args.first() else {
+        eprint!("error: expected a .hack file to disassemble\n{}", USAGE);
+        process::exit(1);
     };
+    let symbols_at = args.iter().position(|arg| arg == "--symbols");
 
-    let d = match &instruct.dest {
-        Some(s) => handle_dest_instruct(&s),
-        None => ['0'; 3],
+    let contents = fs::read_to_string(file_path).expect("Can't read file!");
+    let words = match read_hack(&contents) {
+        Ok(words) => words,
+        Err(mut errors) => {
+            errors.path = file_path.to_string();
+            eprint!("{}", errors);
+            process::exit(1);
+        }
     };
 
-    let mut result = ['1'; PLATFORM_BYTES];
-    result[..3].copy_from_slice(&placeholder);
-    result[3..4].copy_from_slice(&a);
-    result[4..10].copy_from_slice(&c);
-    result[10..13].copy_from_slice(&d);
-    result[13..16].copy_from_slice(&j);
-    return result;
-}
-
-fn decimal_to_binary(dec: u32) -> [char; PLATFORM_BYTES] {
-    const TWO: u32 = 2;
-    let mut b = ['0'; PLATFORM_BYTES];
-    let mut num = dec;
-    while num > 0 {
-        let mut n = 0;
-        while TWO.pow(n + 1) <= num {
-            n += 1;
-        }
-        num = num - TWO.pow(n);
-        if n < b.len() as u32 {
-            let idx = PLATFORM_BYTES - 1 - n as usize;
-            b[idx] = '1';
+    let symbols = symbols_at.map(|at| match args.get(at + 1) {
+        Some(sym_path) if !sym_path.starts_with("--") => {
+            let contents = fs::read_to_string(sym_path).expect("Can't read symbol file!");
+            SymbolMap::parse_text(&contents).unwrap_or_else(|mut errors| {
+                errors.path = sym_path.to_string();
+                eprint!("{}", errors);
+                process::exit(1);
+            })
         }
+        _ => SymbolMap::predefined(),
+    });
+    let disassembly = disassemble(&words, symbols.as_ref());
+    for addr in &disassembly.undecodable {
+        eprintln!("warning: undecodable word at ROM address {}", addr);
     }
-    return b;
-}
 
-fn parse_asm(contents: &str, symbol_table: &HashMap<String, u32>) -> String {
-    let mut hack_file: String = String::new();
-    for line in contents.lines() {
-        let instruction = remove_all_whitespace(line);
-
-        if line.is_empty() || instruction.starts_with('/') || instruction.starts_with('(') {
-            continue;
-        }
-
-        let hack_line: String = if instruction.starts_with('@') {
-            let symbol = parse_a_instruction(&instruction);
-            let addr = get_address(&symbol, symbol_table);
-            let addr = decimal_to_binary(addr);
-            addr.iter().collect()
-        } else {
-            let c_instruct = parse_c_instruction(&instruction);
-            let c_instruct = handle_c_instruction(&c_instruct);
-            c_instruct.iter().collect()
-        };
+    fs::write(output_name(file_path, "dis.asm"), disassembly.asm).expect("Can't write file!");
+}
 
-        hack_file.push_str(&hack_line);
-        hack_file.push_str("\n");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outputs_are_named_after_the_stem() {
+        assert_eq!(output_name("projects/6/max/Max.asm", "hack"), "./Max.hack");
+        assert_eq!(output_name("prog.s", "lst"), "./prog.lst");
+        assert_eq!(output_name("a.asm.d/x.asm", "sym.json"), "./x.sym.json");
+        assert_eq!(output_name("foo.asmx", "hack"), "./foo.hack");
+        assert_eq!(output_name("Max.hack", "dis.asm"), "./Max.dis.asm");
     }
-
-    return hack_file;
 }
@@ -0,0 +1,467 @@
+use std::collections::HashSet;
+use std::mem;
+
+use crate::Program;
+use crate::SymbolTable;
+use crate::instruction::Comp;
+use crate::instruction::Dest;
+use crate::instruction::Instruction;
+use crate::instruction::Jump;
+use crate::parser::MAX_CONSTANT;
+
+// Instruction counts around an optimize pass
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptimizeStats {
+    pub before: usize,
+    pub after: usize,
+}
+
+// Peephole pass over the assembled program, repeated until nothing changes.
+// A label marks where a block can be entered from elsewhere so no rewrite
+// spans one, and every @label is moved to the label's new address afterwards.
+// A plain number right before a jump (@95 then 0;JMP, as the official VM
+// translator writes for its shared call and return code) is a ROM address
+// too, it is treated like a label and moved the same way. Only jumping past
+// the end of the program is an error.
+pub fn optimize(program: &mut Program) -> Result<OptimizeStats, String> {
+    let before = program.instructions.len();
+    // indices of the @number loads that feed a jump
+    let mut fixed = Vec::new();
+    for (i, pair) in program.instructions.windows(2).enumerate() {
+        if let [
+            Instruction::A {
+                value,
+                symbol: None,
+            },
+            Instruction::C { jump, .. },
+        ] = pair
+            && *jump != Jump::Null
+        {
+            if *value as usize > before {
+                let line = &program.source_lines[i];
+                return Err(format!(
+                    "line {} jumps to ROM address {} past the end of the program",
+                    line.line_num, value
+                ));
+            }
+            fixed.push(i);
+        }
+    }
+    while let Some(slots) = rewrite(&program.instructions, &program.labels, &fixed) {
+        fixed = apply(program, slots, &fixed);
+    }
+    program.words = program.instructions.iter().map(|i| i.encode()).collect();
+    Ok(OptimizeStats {
+        before,
+        after: program.instructions.len(),
+    })
+}
+
+// One slot per instruction, None where it was removed. Returns None when
+// nothing could be improved
+fn rewrite(
+    instructions: &[Instruction],
+    labels: &SymbolTable,
+    fixed: &[usize],
+) -> Option<Vec<Option<Instruction>>> {
+    let mut targets: HashSet<usize> = labels.values().map(|addr| *addr as usize).collect();
+    for i in fixed {
+        if let Instruction::A { value, .. } = &instructions[*i] {
+            targets.insert(*value as usize);
+        }
+    }
+    let fixed: HashSet<usize> = fixed.iter().copied().collect();
+    let mut slots: Vec<Option<Instruction>> = instructions.iter().cloned().map(Some).collect();
+    let mut changed = false;
+    // the load A is known to hold, forgotten at labels and after anything
+    // writing A. Label loads are never known, their value moves with the code
+    // so it can't stand in for a constant or be stood in for by one.
+    let mut known_a: Option<(u16, Option<&String>)> = None;
+    let mut i = 0;
+    while i < instructions.len() {
+        if targets.contains(&i) {
+            known_a = None;
+        }
+        let next = instructions.get(i + 1);
+        match &instructions[i] {
+            // kept as is, its value changes once the code moves
+            Instruction::A { .. } if fixed.contains(&i) => known_a = None,
+            Instruction::A { value, symbol } => {
+                // A already holds it, or the next load overwrites it unused
+                if known_a == Some((*value, symbol.as_ref()))
+                    || matches!(next, Some(Instruction::A { .. }))
+                {
+                    slots[i] = None;
+                    changed = true;
+                } else if symbol.is_none()
+                    && let Some((folded, len)) =
+                        fold_constant(*value, &instructions[i..], &targets, i)
+                {
+                    for (offset, slot) in folded.into_iter().enumerate() {
+                        slots[i + offset] = slot;
+                    }
+                    changed = true;
+                    known_a = None;
+                    i += len;
+                    continue;
+                } else if symbol.as_ref().is_some_and(|s| labels.contains_key(s)) {
+                    known_a = None;
+                } else {
+                    known_a = Some((*value, symbol.as_ref()));
+                }
+            }
+            Instruction::C { dest, .. } => {
+                if !targets.contains(&(i + 1))
+                    && let Some(next) = next
+                    && cancels(&instructions[i], next)
+                {
+                    slots[i] = None;
+                    slots[i + 1] = None;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+                if dest.writes_a() {
+                    known_a = None;
+                }
+            }
+        }
+        i += 1;
+    }
+    changed.then_some(slots)
+}
+
+// `@n D=A D=D+1` becomes `@n+1 D=A` and `@0 D=A` / `@1 D=A` become `D=0` / `D=1`,
+// only when the following instruction reloads A so its old value is never read.
+// Returns the replacement slots starting at `code[0]`
+fn fold_constant(
+    value: u16,
+    code: &[Instruction],
+    targets: &HashSet<usize>,
+    at: usize,
+) -> Option<(Vec<Option<Instruction>>, usize)> {
+    let is_c = |i: usize, comp: Comp| {
+        code.get(i)
+            == Some(&Instruction::C {
+                comp,
+                dest: Dest::D,
+                jump: Jump::Null,
+            })
+    };
+    let reloads_a = |i: usize| matches!(code.get(i), Some(Instruction::A { .. }));
+    let entered = |i: usize| targets.contains(&(at + i));
+    if !is_c(1, Comp::A) || entered(1) {
+        return None;
+    }
+
+    let step = if is_c(2, Comp::DPlusOne) {
+        Some(
+            value
+                .checked_add(1)
+                .filter(|v| u32::from(*v) <= MAX_CONSTANT),
+        )
+    } else if is_c(2, Comp::DMinusOne) {
+        Some(value.checked_sub(1))
+    } else {
+        None
+    };
+    if let Some(Some(folded)) = step
+        && !entered(2)
+        && reloads_a(3)
+    {
+        let load = Instruction::A {
+            value: folded,
+            symbol: None,
+        };
+        return Some((vec![Some(load), Some(code[1].clone()), None], 3));
+    }
+
+    let comp = match value {
+        0 => Comp::Zero,
+        1 => Comp::One,
+        _ => return None,
+    };
+    if !reloads_a(2) {
+        return None;
+    }
+    let set_d = Instruction::C {
+        comp,
+        dest: Dest::D,
+        jump: Jump::Null,
+    };
+    Some((vec![None, Some(set_d)], 2))
+}
+
+// Adjacent increment and decrement of the same register, e.g. M=M+1 M=M-1
+fn cancels(first: &Instruction, second: &Instruction) -> bool {
+    let (
+        Instruction::C {
+            comp: a,
+            dest: a_dest,
+            jump: Jump::Null,
+        },
+        Instruction::C {
+            comp: b,
+            dest: b_dest,
+            jump: Jump::Null,
+        },
+    ) = (first, second)
+    else {
+        return false;
+    };
+    if a_dest != b_dest {
+        return false;
+    }
+    let pair = match a_dest {
+        Dest::M => (Comp::MPlusOne, Comp::MMinusOne),
+        Dest::D => (Comp::DPlusOne, Comp::DMinusOne),
+        Dest::A => (Comp::APlusOne, Comp::AMinusOne),
+        _ => return false,
+    };
+    (*a, *b) == pair || (*b, *a) == pair
+}
+
+// Drops the removed slots and moves labels and fixed jump addresses to where
+// their instruction ended up. Returns the new indices of the fixed loads
+fn apply(program: &mut Program, slots: Vec<Option<Instruction>>, fixed: &[usize]) -> Vec<usize> {
+    // new address of every old one, plus one past the end for trailing labels
+    let mut moved = Vec::with_capacity(slots.len() + 1);
+    let mut kept: u16 = 0;
+    for slot in &slots {
+        moved.push(kept);
+        if slot.is_some() {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+    for (name, addr) in program.labels.iter_mut() {
+        *addr = moved[*addr as usize];
+        program.symbols.insert(name.clone(), *addr);
+    }
+
+    let source_lines = mem::take(&mut program.source_lines);
+    program.instructions.clear();
+    for (slot, line) in slots.into_iter().zip(source_lines) {
+        let Some(mut instruction) = slot else {
+            continue;
+        };
+        if let Instruction::A {
+            value,
+            symbol: Some(symbol),
+        } = &mut instruction
+            && let Some(addr) = program.labels.get(symbol)
+        {
+            *value = *addr;
+        }
+        program.instructions.push(instruction);
+        program.source_lines.push(line);
+    }
+
+    let mut relocated = Vec::with_capacity(fixed.len());
+    for i in fixed {
+        let at = moved[*i] as usize;
+        if let Instruction::A { value, .. } = &mut program.instructions[at] {
+            *value = moved[*value as usize];
+        }
+        relocated.push(at);
+    }
+    relocated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::Path;
+
+    use crate::assemble;
+
+    fn optimized(source: &str) -> Vec<String> {
+        let mut program = assemble(source).unwrap();
+        optimize(&mut program).unwrap();
+        program.instructions.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn increment_and_decrement_cancel() {
+        assert_eq!(optimized("@SP\nM=M+1\nM=M-1\nD=M\n"), ["@SP", "D=M"]);
+        assert_eq!(
+            optimized("@SP\nD=M\nD=D-1\nD=D+1\n@R1\nM=D\n"),
+            ["@SP", "D=M", "@R1", "M=D"]
+        );
+    }
+
+    #[test]
+    fn a_is_not_reloaded_with_what_it_holds() {
+        assert_eq!(optimized("@R1\nD=M\n@R1\nM=D+1\n"), ["@R1", "D=M", "M=D+1"]);
+        // a label can be entered with anything in A
+        assert_eq!(
+            optimized("@R1\nD=M\n(LOOP)\n@R1\nM=D+1\n@LOOP\n0;JMP\n"),
+            ["@R1", "D=M", "@R1", "M=D+1", "@LOOP", "0;JMP"]
+        );
+    }
+
+    #[test]
+    fn label_loads_are_not_mistaken_for_constants() {
+        let source = "@5\nD=A\n@LOOP\n0;JMP\n@R0\n(LOOP)\n@R1\nM=D\n(END)\n@END\n0;JMP\n";
+        assert_eq!(
+            optimized(source),
+            ["@5", "D=A", "@LOOP", "0;JMP", "@R1", "M=D", "@END", "0;JMP"]
+        );
+        let mut program = assemble(source).unwrap();
+        optimize(&mut program).unwrap();
+        assert_eq!(program.words[2], program.labels["LOOP"]);
+        assert_eq!(program.labels["LOOP"], 4);
+
+        // and the other way round, a constant after a label load
+        let source = "@LOOP\nD=A\n@5\nM=D\n@R0\n(LOOP)\n@R1\nM=D\n";
+        assert_eq!(
+            optimized(source),
+            ["@LOOP", "D=A", "@5", "M=D", "@R1", "M=D"]
+        );
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        assert_eq!(
+            optimized("@5\nD=A\nD=D+1\n@R0\nM=D\n"),
+            ["@6", "D=A", "@R0", "M=D"]
+        );
+        assert_eq!(
+            optimized("@5\nD=A\nD=D-1\n@R0\nM=D\n"),
+            ["@4", "D=A", "@R0", "M=D"]
+        );
+        assert_eq!(optimized("@1\nD=A\n@R0\nM=D\n"), ["D=1", "@R0", "M=D"]);
+        // A is read after D=A, so it must keep its value
+        assert_eq!(
+            optimized("@5\nD=A\nD=D+1\nM=D\n"),
+            ["@5", "D=A", "D=D+1", "M=D"]
+        );
+    }
+
+    #[test]
+    fn fixed_jump_addresses_follow_the_code() {
+        let source = "@R0\nM=M+1\nM=M-1\n@7\n0;JMP\n@R1\nM=1\n@R2\nM=1\n";
+        assert_eq!(
+            optimized(source),
+            ["@4", "0;JMP", "@R1", "M=1", "@R2", "M=1"]
+        );
+        let mut program = assemble("@100\n0;JMP\n").unwrap();
+        assert!(optimize(&mut program).is_err());
+    }
+
+    #[test]
+    fn pong_jumps_land_where_they_did() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../pong/Pong.asm");
+        let source = fs::read_to_string(path).unwrap();
+        let before = assemble(&source).unwrap();
+        let mut program = assemble(&source).unwrap();
+        let stats = optimize(&mut program).unwrap();
+        assert!(stats.after < stats.before);
+
+        let jump_targets = |program: &Program| -> Vec<usize> {
+            program
+                .instructions
+                .windows(2)
+                .filter_map(|pair| match pair {
+                    [
+                        Instruction::A {
+                            value,
+                            symbol: None,
+                        },
+                        Instruction::C { jump, .. },
+                    ] if *jump != Jump::Null => {
+                        Some(program.source_lines[*value as usize].line_num)
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        // a removed target is landed on at the next instruction still there
+        let kept: Vec<usize> = program.source_lines.iter().map(|l| l.line_num).collect();
+        let expected: Vec<usize> = jump_targets(&before)
+            .into_iter()
+            .map(|line| *kept.iter().find(|kept| **kept >= line).unwrap())
+            .collect();
+        assert_eq!(jump_targets(&program), expected);
+    }
+
+    // Just enough of a Hack CPU to compare RAM after a run
+    fn run(words: &[u16], ram: &mut [u16], cycles: usize) {
+        let (mut a, mut d, mut pc) = (0u16, 0u16, 0usize);
+        for _ in 0..cycles {
+            let Some(&word) = words.get(pc) else {
+                return;
+            };
+            if word & 0x8000 == 0 {
+                a = word;
+                pc += 1;
+                continue;
+            }
+            let y = if word & 0x1000 != 0 {
+                ram[a as usize]
+            } else {
+                a
+            };
+            let bits = word >> 6;
+            let mut x = if bits & 0b100000 != 0 { 0 } else { d };
+            if bits & 0b010000 != 0 {
+                x = !x;
+            }
+            let mut y = if bits & 0b001000 != 0 { 0 } else { y };
+            if bits & 0b000100 != 0 {
+                y = !y;
+            }
+            let mut out = if bits & 0b000010 != 0 {
+                x.wrapping_add(y)
+            } else {
+                x & y
+            };
+            if bits & 0b000001 != 0 {
+                out = !out;
+            }
+            if word & 0b001000 != 0 {
+                ram[a as usize] = out;
+            }
+            let target = a;
+            if word & 0b100000 != 0 {
+                a = out;
+            }
+            if word & 0b010000 != 0 {
+                d = out;
+            }
+            let out = out as i16;
+            let jump = (word & 0b100 != 0 && out < 0)
+                || (word & 0b010 != 0 && out == 0)
+                || (word & 0b001 != 0 && out > 0);
+            pc = if jump { target as usize } else { pc + 1 };
+        }
+    }
+
+    #[test]
+    fn project_7_programs_leave_the_same_ram() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../7");
+        for test in [
+            "StackArithmetic/SimpleAdd/SimpleAdd.asm",
+            "StackArithmetic/StackTest/StackTest.asm",
+            "MemoryAccess/BasicTest/BasicTest.asm",
+            "MemoryAccess/PointerTest/PointerTest.asm",
+            "MemoryAccess/StaticTest/StaticTest.asm",
+        ] {
+            let source = fs::read_to_string(root.join(test)).unwrap();
+            let plain = assemble(&source).unwrap();
+            let mut fast = assemble(&source).unwrap();
+            let stats = optimize(&mut fast).unwrap();
+            assert!(stats.after < stats.before, "{}", test);
+
+            // the pointers the test scripts set up
+            let mut start = vec![0u16; 32768];
+            start[..5].copy_from_slice(&[256, 300, 400, 3000, 3010]);
+            let (mut expected, mut actual) = (start.clone(), start);
+            run(&plain.words, &mut expected, 10_000);
+            run(&fast.words, &mut actual, 10_000);
+            assert!(expected == actual, "{} changed what it computes", test);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::io;
+use std::io::Write;
+
+// Ways to write out assembled words for the different tools that load them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // ASCII '0'/'1' lines, what the nand2tetris tools read
+    Hack,
+    BinaryBigEndian,
+    BinaryLittleEndian,
+    IntelHex,
+    // Logisim ROM component image
+    Logisim,
+    // for Verilog $readmemb and $readmemh
+    VerilogBin,
+    VerilogHex,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<OutputFormat> {
+        let format = match name {
+            "hack" => OutputFormat::Hack,
+            "bin" | "bin-be" => OutputFormat::BinaryBigEndian,
+            "bin-le" => OutputFormat::BinaryLittleEndian,
+            "ihex" => OutputFormat::IntelHex,
+            "logisim" => OutputFormat::Logisim,
+            "memb" => OutputFormat::VerilogBin,
+            "memh" => OutputFormat::VerilogHex,
+            _ => return None,
+        };
+        Some(format)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Hack => "hack",
+            OutputFormat::BinaryBigEndian => "bin",
+            OutputFormat::BinaryLittleEndian => "le.bin",
+            OutputFormat::IntelHex => "hex",
+            OutputFormat::Logisim => "rom",
+            OutputFormat::VerilogBin => "memb",
+            OutputFormat::VerilogHex => "memh",
+        }
+    }
+
+    // true if words can be written in pieces, one after another, without
+    // knowing the whole image
+    pub fn streams(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Hack | OutputFormat::BinaryBigEndian | OutputFormat::BinaryLittleEndian
+        )
+    }
+
+    // Hack text goes straight to the sink a word at a time, the rest are
+    // encoded first since they need the whole image
+    pub fn write<W: Write>(&self, words: &[u16], out: &mut W) -> io::Result<()> {
+        match self {
+            OutputFormat::Hack => {
+                for word in words {
+                    writeln!(out, "{:016b}", word)?;
+                }
+                Ok(())
+            }
+            _ => out.write_all(&self.encode(words)),
+        }
+    }
+
+    pub fn encode(&self, words: &[u16]) -> Vec<u8> {
+        match self {
+            OutputFormat::Hack => words
+                .iter()
+                .map(|w| format!("{:016b}\n", w))
+                .collect::<String>()
+                .into_bytes(),
+            OutputFormat::BinaryBigEndian => words.iter().flat_map(|w| w.to_be_bytes()).collect(),
+            OutputFormat::BinaryLittleEndian => {
+                words.iter().flat_map(|w| w.to_le_bytes()).collect()
+            }
+            OutputFormat::IntelHex => intel_hex(words).into_bytes(),
+            OutputFormat::Logisim => logisim(words).into_bytes(),
+            OutputFormat::VerilogBin => verilog(words, |w| format!("{:016b}", w)).into_bytes(),
+            OutputFormat::VerilogHex => verilog(words, |w| format!("{:04x}", w)).into_bytes(),
+        }
+    }
+}
+
+// Words are stored big-endian, two bytes per ROM address
+fn intel_hex(words: &[u16]) -> String {
+    const RECORD_LEN: usize = 16;
+    let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_be_bytes()).collect();
+    let mut hex = String::new();
+    let mut upper = 0;
+    for (i, chunk) in bytes.chunks(RECORD_LEN).enumerate() {
+        let offset = i * RECORD_LEN;
+        // extended linear address record when crossing a 64K boundary
+        if offset >> 16 != upper {
+            upper = offset >> 16;
+            hex.push_str(&hex_record(0, 0x04, &(upper as u16).to_be_bytes()));
+        }
+        hex.push_str(&hex_record(offset as u16, 0x00, chunk));
+    }
+    hex.push_str(&hex_record(0, 0x01, &[]));
+    hex
+}
+
+fn hex_record(addr: u16, kind: u8, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend(addr.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    record.push(sum.wrapping_neg());
+
+    let mut line = String::from(":");
+    for byte in record {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line.push('\n');
+    line
+}
+
+// "v2.0 raw" image, runs of the same word are written as count*value
+fn logisim(words: &[u16]) -> String {
+    let mut rom = String::from("v2.0 raw\n");
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let run = words[i..].iter().take_while(|w| **w == words[i]).count();
+        if run >= 4 {
+            entries.push(format!("{}*{:x}", run, words[i]));
+        } else {
+            for _ in 0..run {
+                entries.push(format!("{:x}", words[i]));
+            }
+        }
+        i += run;
+    }
+    for line in entries.chunks(8) {
+        rom.push_str(&line.join(" "));
+        rom.push('\n');
+    }
+    rom
+}
+
+fn verilog(words: &[u16], word_to_text: fn(u16) -> String) -> String {
+    let mut mem = format!("// Hack ROM, {} words\n@0\n", words.len());
+    for word in words {
+        mem.push_str(&word_to_text(*word));
+        mem.push('\n');
+    }
+    mem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(format: OutputFormat, words: &[u16]) -> String {
+        String::from_utf8(format.encode(words)).unwrap()
+    }
+
+    #[test]
+    fn intel_hex_records_carry_checksums() {
+        let words = [0x0002, 0xEC10, 0x0003, 0xE090];
+        assert_eq!(
+            text(OutputFormat::IntelHex, &words),
+            ":080000000002EC100003E09087\n:00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn intel_hex_splits_records_and_crosses_64k() {
+        // 16 bytes a record, the 4097th record starts at byte 0x10000
+        let words = vec![0xFFFF; 0x8001];
+        let hex = text(OutputFormat::IntelHex, &words);
+        let lines: Vec<&str> = hex.lines().collect();
+        assert_eq!(lines[0], format!(":10000000{}00", "F".repeat(32)));
+        assert_eq!(lines[4096], ":020000040001F9");
+        assert_eq!(lines[4097], ":02000000FFFF00");
+        assert_eq!(lines[4098], ":00000001FF");
+        assert_eq!(lines.len(), 4099);
+    }
+
+    #[test]
+    fn logisim_runs_of_four_or_more_are_counted() {
+        let words = [7, 7, 7, 0, 0, 0, 0, 0x10, 0xABCD];
+        assert_eq!(
+            text(OutputFormat::Logisim, &words),
+            "v2.0 raw\n7 7 7 4*0 10 abcd\n"
+        );
+        let words = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(
+            text(OutputFormat::Logisim, &words),
+            "v2.0 raw\n1 2 3 4 5 6 7 8\n9\n"
+        );
+    }
+
+    #[test]
+    fn verilog_images_have_a_header() {
+        let words = [0x0002, 0xEC10];
+        assert_eq!(
+            text(OutputFormat::VerilogBin, &words),
+            "// Hack ROM, 2 words\n@0\n0000000000000010\n1110110000010000\n"
+        );
+        assert_eq!(
+            text(OutputFormat::VerilogHex, &words),
+            "// Hack ROM, 2 words\n@0\n0002\nec10\n"
+        );
+    }
+
+    #[test]
+    fn binaries_are_two_bytes_a_word() {
+        assert_eq!(
+            OutputFormat::BinaryBigEndian.encode(&[0xEC10]),
+            [0xEC, 0x10]
+        );
+        assert_eq!(
+            OutputFormat::BinaryLittleEndian.encode(&[0xEC10]),
+            [0x10, 0xEC]
+        );
+        assert_eq!(text(OutputFormat::Hack, &[2]), "0000000000000010\n");
+    }
+}
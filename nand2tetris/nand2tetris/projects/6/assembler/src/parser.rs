@@ -0,0 +1,345 @@
+use crate::error::SpanError;
+use crate::instruction::Dest;
+use crate::instruction::Instruction;
+use crate::instruction::Jump;
+use crate::instruction::handle_comp_instruct;
+use crate::instruction::handle_dest_instruct;
+use crate::instruction::handle_jmp_instruct;
+use crate::macros::Expansion;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Label,
+    AInstruction,
+    CInstruction,
+}
+
+// One instruction or label with comments and whitespace stripped
+#[derive(Debug)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: String,
+    // source column of every char in text
+    pub cols: Vec<usize>,
+    // index into the expansion's files
+    pub file: usize,
+    pub line_num: usize,
+    pub source: &'a str,
+    // came from a macro body
+    pub expanded: bool,
+    // prefix for `.local` symbols in this token's file
+    pub scope: &'a str,
+}
+
+#[derive(Debug)]
+pub struct CInstruction {
+    pub comp: String,
+    pub dest: Option<String>,
+    pub jmp: Option<String>,
+    // offsets of each part inside the stripped instruction
+    pub comp_at: usize,
+    pub jmp_at: usize,
+}
+
+// Splits the source into tokens, `//` starts a comment anywhere on a line
+pub fn tokenize(expansion: &Expansion) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for source_line in &expansion.lines {
+        let line = source_line.text.as_str();
+        let code = match line.find("//") {
+            Some(at) => &line[..at],
+            None => line,
+        };
+        let (text, cols) = remove_all_whitespace(code);
+        if text.is_empty() {
+            continue;
+        }
+        let kind = if text.starts_with('(') {
+            TokenKind::Label
+        } else if text.starts_with('@') {
+            TokenKind::AInstruction
+        } else {
+            TokenKind::CInstruction
+        };
+        tokens.push(Token {
+            kind,
+            text,
+            cols,
+            file: source_line.file,
+            line_num: source_line.line_num,
+            source: line,
+            expanded: source_line.expanded,
+            scope: &expansion.scopes[source_line.file],
+        });
+    }
+    tokens
+}
+
+// Also returns the source column of every kept char so errors can point at the line
+pub fn remove_all_whitespace(s: &str) -> (String, Vec<usize>) {
+    let mut instruction = String::new();
+    let mut cols = Vec::new();
+    for (col, ch) in s.chars().enumerate() {
+        if !ch.is_whitespace() {
+            instruction.push(ch);
+            cols.push(col);
+        }
+    }
+    (instruction, cols)
+}
+
+// Symbols are letters, digits, `_`, `.`, `$` and `:` and can't start with a digit
+pub fn is_identifier(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && !symbol.starts_with(|c: char| c.is_ascii_digit())
+        && symbol
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.$:".contains(c))
+}
+
+// Symbols starting with `.` are private to their file, e.g. `.loop` in
+// Mult.asm becomes Mult$0.loop, everything else is global
+pub fn qualify(symbol: &str, scope: &str) -> String {
+    if symbol.starts_with('.') {
+        format!("{}{}", scope, symbol)
+    } else {
+        symbol.to_string()
+    }
+}
+
+pub fn parse_a_instruction(instruction: &str) -> Result<String, SpanError> {
+    let split: Vec<&str> = instruction.split("@").collect();
+    if split.len() == 2 && !split[1].is_empty() {
+        let symbol = split[1];
+        if !is_constant(symbol) && !is_identifier(symbol) {
+            return Err(SpanError::new(
+                format!("`{}` is not a valid symbol", symbol),
+                1,
+                symbol.chars().count(),
+            ));
+        }
+        Ok(symbol.to_string())
+    } else {
+        Err(SpanError::new(
+            format!("malformed A instruction `{}`", instruction),
+            0,
+            instruction.chars().count(),
+        ))
+    }
+}
+
+// Largest value an A instruction can load, bit 15 is the opcode
+pub const MAX_CONSTANT: u32 = 0x7FFF;
+// Most negative literal, -16384 is 0x4000 in 15 bit two's complement
+pub const MIN_NEGATIVE: u32 = 0x4000;
+
+// true for anything that should be read as a number rather than a symbol
+pub fn is_constant(symbol: &str) -> bool {
+    symbol.starts_with(|c: char| c.is_ascii_digit() || c == '-')
+}
+
+// Accepts decimal, 0x hex and 0b binary with optional `_` separators.
+// A negative literal -n (1 <= n <= 16384) is stored as its 15 bit two's
+// complement 32768 - n, so @-1 loads 0x7FFF. A can't hold the 16 bit form
+// since bit 15 is the opcode, follow with D=A and sign extend in code if needed.
+pub fn parse_constant(symbol: &str) -> Result<u16, String> {
+    let (negative, digits) = match symbol.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, symbol),
+    };
+    let cleaned = digits.replace('_', "");
+    let parsed = if let Some(hex) = cleaned.strip_prefix("0x").or(cleaned.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(bin) = cleaned.strip_prefix("0b").or(cleaned.strip_prefix("0B")) {
+        u32::from_str_radix(bin, 2)
+    } else {
+        cleaned.parse::<u32>()
+    };
+
+    let value = match parsed {
+        Ok(value) => value,
+        Err(_) => return Err(format!("invalid constant `{}`", symbol)),
+    };
+    if negative {
+        if value == 0 || value > MIN_NEGATIVE {
+            return Err(format!(
+                "constant `{}` is out of range, negative values must be between -{} and -1",
+                symbol, MIN_NEGATIVE
+            ));
+        }
+        return Ok((MAX_CONSTANT + 1 - value) as u16);
+    }
+    if value > MAX_CONSTANT {
+        return Err(format!(
+            "constant `{}` does not fit in 15 bits (max {})",
+            symbol, MAX_CONSTANT
+        ));
+    }
+    Ok(value as u16)
+}
+
+pub fn parse_label(instruction: &str) -> Result<String, SpanError> {
+    let len = instruction.chars().count();
+    if !instruction.ends_with(')') {
+        return Err(SpanError::new(
+            format!("label `{}` is missing a closing `)`", instruction),
+            0,
+            len,
+        ));
+    }
+    let symbol = &instruction[1..instruction.len() - 1];
+    if !is_identifier(symbol) {
+        return Err(SpanError::new(
+            format!("`{}` is not a valid label name", symbol),
+            1,
+            len.saturating_sub(2).max(1),
+        ));
+    }
+    Ok(symbol.to_string())
+}
+
+pub fn parse_c_instruction(instruction: &str) -> Result<CInstruction, SpanError> {
+    let mut c_instruct = CInstruction {
+        comp: String::new(),
+        dest: None,
+        jmp: None,
+        comp_at: 0,
+        jmp_at: 0,
+    };
+    let malformed = || {
+        SpanError::new(
+            format!("malformed C instruction `{}`", instruction),
+            0,
+            instruction.chars().count(),
+        )
+    };
+
+    let split_jmp: Vec<&str> = instruction.split(";").collect();
+    if split_jmp.len() == 2 {
+        c_instruct.jmp = Some(split_jmp[1].to_string());
+        c_instruct.jmp_at = split_jmp[0].chars().count() + 1;
+    } else if split_jmp.len() > 2 {
+        return Err(malformed());
+    }
+
+    let split_dest: Vec<&str> = split_jmp[0].split("=").collect();
+
+    if split_dest.len() == 1 {
+        c_instruct.comp = split_dest[0].to_string();
+    } else if split_dest.len() == 2 {
+        c_instruct.dest = Some(split_dest[0].to_string());
+        c_instruct.comp = split_dest[1].to_string();
+        c_instruct.comp_at = split_dest[0].chars().count() + 1;
+    } else {
+        return Err(malformed());
+    }
+
+    Ok(c_instruct)
+}
+
+// extended allows the shift comps of the extended Hack ISA
+pub fn handle_c_instruction(
+    instruct: &CInstruction,
+    extended: bool,
+) -> Result<Instruction, SpanError> {
+    let comp_span = |e| SpanError::new(e, instruct.comp_at, instruct.comp.chars().count());
+    let comp = handle_comp_instruct(&instruct.comp).map_err(comp_span)?;
+    if comp.is_extended() && !extended {
+        return Err(comp_span(format!(
+            "`{}` is an extended Hack instruction, enable it with --extended",
+            instruct.comp
+        )));
+    }
+    let jump = match &instruct.jmp {
+        Some(s) => handle_jmp_instruct(s)
+            .map_err(|e| SpanError::new(e, instruct.jmp_at, s.chars().count()))?,
+        None => Jump::Null,
+    };
+
+    let dest = match &instruct.dest {
+        Some(s) => handle_dest_instruct(s).map_err(|e| SpanError::new(e, 0, s.chars().count()))?,
+        None => Dest::Null,
+    };
+
+    Ok(Instruction::C { comp, dest, jump })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assemble;
+
+    #[test]
+    fn literals_in_every_base() {
+        for (literal, value) in [
+            ("0", 0),
+            ("32767", 0x7FFF),
+            ("0x7FFF", 0x7FFF),
+            ("0X1f", 0x1F),
+            ("0b101", 5),
+            ("0B1000_0000", 128),
+            ("16_384", 16384),
+            ("-1", 0x7FFF),
+            ("-0x10", 0x7FF0),
+            ("-16384", 0x4000),
+        ] {
+            assert_eq!(parse_constant(literal), Ok(value), "{}", literal);
+        }
+    }
+
+    #[test]
+    fn constants_out_of_range_are_errors() {
+        for (literal, message) in [
+            (
+                "32768",
+                "constant `32768` does not fit in 15 bits (max 32767)",
+            ),
+            (
+                "0x8000",
+                "constant `0x8000` does not fit in 15 bits (max 32767)",
+            ),
+            (
+                "-16385",
+                "constant `-16385` is out of range, negative values must be between -16384 and -1",
+            ),
+            (
+                "-0",
+                "constant `-0` is out of range, negative values must be between -16384 and -1",
+            ),
+            ("0x", "invalid constant `0x`"),
+            ("0b102", "invalid constant `0b102`"),
+            ("99999999999", "invalid constant `99999999999`"),
+            ("12a", "invalid constant `12a`"),
+        ] {
+            assert_eq!(parse_constant(literal), Err(message.to_string()));
+        }
+    }
+
+    #[test]
+    fn trailing_comments_are_stripped() {
+        let commented =
+            assemble("@5 // five\nD=A// no space\n(END)  // loop\n@END\n0;JMP//\n").unwrap();
+        let plain = assemble("@5\nD=A\n(END)\n@END\n0;JMP\n").unwrap();
+        assert_eq!(commented.words, plain.words);
+        assert_eq!(commented.labels["END"], 2);
+    }
+
+    #[test]
+    fn bad_label_names_are_errors() {
+        assert_eq!(parse_label("(Main.loop$1:x_)").unwrap(), "Main.loop$1:x_");
+        for (label, message, at, len) in [
+            ("(1st)", "`1st` is not a valid label name", 1, 3),
+            ("(a-b)", "`a-b` is not a valid label name", 1, 3),
+            ("()", "`` is not a valid label name", 1, 1),
+            ("(LOOP", "label `(LOOP` is missing a closing `)`", 0, 5),
+        ] {
+            let err = parse_label(label).unwrap_err();
+            assert_eq!((err.message.as_str(), err.at, err.len), (message, at, len));
+        }
+
+        let err = assemble("D=A\n  (LOOP+1)\n").unwrap_err();
+        let diag = &err.diagnostics[0];
+        assert_eq!((diag.line, diag.col, diag.text.as_str()), (2, 4, "LOOP+1"));
+    }
+}
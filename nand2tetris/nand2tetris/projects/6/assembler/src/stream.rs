@@ -0,0 +1,569 @@
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::mem;
+
+use crate::AsmOptions;
+use crate::ROM_SIZE;
+use crate::SymbolTable;
+use crate::VARIABLE_END;
+use crate::analysis::jumps_and_data;
+use crate::analysis::jumps_to_variable;
+use crate::error::AsmError;
+use crate::error::Diagnostic;
+use crate::error::SpanError;
+use crate::init_symbol_table;
+use crate::instruction::Instruction;
+use crate::instruction::Jump;
+use crate::macros::scope_name;
+use crate::output::OutputFormat;
+use crate::parser::MAX_CONSTANT;
+use crate::parser::handle_c_instruction;
+use crate::parser::is_constant;
+use crate::parser::is_identifier;
+use crate::parser::parse_a_instruction;
+use crate::parser::parse_c_instruction;
+use crate::parser::parse_constant;
+use crate::parser::parse_label;
+use crate::parser::qualify;
+use crate::parser::remove_all_whitespace;
+
+// What a streamed assembly wrote and the symbols it found
+#[derive(Debug, Default)]
+pub struct Streamed {
+    // words written to the output
+    pub size: usize,
+    pub labels: SymbolTable,
+    pub variables: SymbolTable,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Streamed {
+    pub fn size_report(&self) -> String {
+        crate::size_report(self.size, self.labels.len(), &self.variables)
+    }
+}
+
+// A symbol used before it was defined
+#[derive(Debug)]
+struct Forward {
+    // words to patch once the address is known
+    uses: Vec<usize>,
+    // where it was first used, for the error if it can't be allocated
+    line_num: usize,
+    source: String,
+}
+
+// Where a symbol was used, for the warnings
+#[derive(Debug, Clone)]
+struct Use {
+    line_num: usize,
+    source: String,
+    len: usize,
+}
+
+// First use of a symbol as a jump target and as a data address, like
+// analysis::check_symbol_usage looks for
+#[derive(Debug, Default)]
+struct Usage {
+    jump: Option<Use>,
+    data: Option<Use>,
+}
+
+struct Assembler<W: Write> {
+    format: OutputFormat,
+    out: W,
+    options: AsmOptions,
+    scope: String,
+    predefined: SymbolTable,
+    symbols: SymbolTable,
+    labels: SymbolTable,
+    variables: SymbolTable,
+    defined_on: HashMap<String, usize>,
+    forward: HashMap<String, Forward>,
+    // forward references in order of first use, leftovers become variables
+    first_use: Vec<String>,
+    // words not written yet, starting at address `written`
+    words: Vec<u16>,
+    written: usize,
+    // addresses still waiting for their symbol
+    unresolved: BTreeSet<usize>,
+    // can pass ROM_SIZE, words stops growing there
+    word_count: usize,
+    reported_full: bool,
+    usages: HashMap<String, Usage>,
+    // the symbol of the last A instruction, checked against the C after it
+    last_a: Option<(String, Use)>,
+    errors: AsmError,
+}
+
+// Single pass assembler for big generated programs. Lines are read one at a
+// time and dropped, a symbol used before its label is recorded and patched
+// when the label turns up, and whatever is still unresolved at the end
+// becomes a variable in order of first use, exactly like the two pass
+// assembler. Each word is written to `out` as soon as it and every word
+// before it are known, so only the stretch from the oldest unresolved
+// symbol on is held. That stretch runs to the end of the input from the
+// first use of a variable, as nothing says it isn't a label until then.
+// `format` must write words one after another, see OutputFormat::streams.
+// The output is incomplete if this fails. Macros and .include need the whole
+// source so only .equ is supported.
+pub fn assemble_stream<R: BufRead, W: Write>(
+    mut input: R,
+    format: OutputFormat,
+    out: W,
+    path: &str,
+    options: AsmOptions,
+) -> Result<Streamed, AsmError> {
+    assert!(
+        format.streams(),
+        "{:?} can't be written a word at a time",
+        format
+    );
+    let mut asm = Assembler {
+        format,
+        out,
+        options,
+        scope: scope_name(path, 0),
+        predefined: init_symbol_table(),
+        symbols: init_symbol_table(),
+        labels: SymbolTable::new(),
+        variables: SymbolTable::new(),
+        defined_on: HashMap::new(),
+        forward: HashMap::new(),
+        first_use: Vec::new(),
+        words: Vec::new(),
+        written: 0,
+        unresolved: BTreeSet::new(),
+        word_count: 0,
+        reported_full: false,
+        usages: HashMap::new(),
+        last_a: None,
+        errors: AsmError::new(path),
+    };
+
+    let mut line = String::new();
+    let mut code = String::new();
+    let mut line_num = 0;
+    loop {
+        line.clear();
+        match input.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => line_num += 1,
+            Err(e) => {
+                let err = SpanError::new(format!("can't read line: {}", e), 0, 1);
+                asm.errors.push(line_num + 1, "", &[], err);
+                break;
+            }
+        }
+        let text = line.trim_end_matches(['\n', '\r']);
+        let raw = match text.find("//") {
+            Some(at) => &text[..at],
+            None => text,
+        };
+        code.clear();
+        code.extend(raw.chars().filter(|c| !c.is_whitespace()));
+        if code.is_empty() {
+            continue;
+        }
+
+        let result = if code.starts_with('(') {
+            asm.label(&code, line_num)
+        } else if code.starts_with('.') {
+            asm.directive(raw, line_num)
+        } else if code.starts_with('@') {
+            asm.a_instruction(&code, line_num, text)
+        } else {
+            asm.c_instruction(&code)
+        };
+        if let Err(err) = result {
+            let (_, cols) = remove_all_whitespace(raw);
+            asm.errors.push(line_num, text, &cols, err);
+        }
+        if asm.word_count > ROM_SIZE && !asm.reported_full {
+            let err = SpanError::new(
+                format!("program is larger than the {} word ROM", ROM_SIZE),
+                0,
+                code.chars().count(),
+            );
+            let (_, cols) = remove_all_whitespace(raw);
+            asm.errors.push(line_num, text, &cols, err);
+            asm.reported_full = true;
+        }
+        if let Err(e) = asm.flush() {
+            let err = SpanError::new(format!("can't write output: {}", e), 0, 1);
+            asm.errors.push(line_num, "", &[], err);
+            return Err(asm.errors);
+        }
+    }
+
+    asm.allocate_variables();
+    asm.check_symbol_usage();
+    if let Err(e) = asm.flush().and_then(|_| asm.out.flush()) {
+        let err = SpanError::new(format!("can't write output: {}", e), 0, 1);
+        asm.errors.push(line_num, "", &[], err);
+    }
+    if asm.errors.has_errors() {
+        return Err(asm.errors);
+    }
+    Ok(Streamed {
+        size: asm.written,
+        labels: asm.labels,
+        variables: asm.variables,
+        warnings: asm.errors.diagnostics,
+    })
+}
+
+impl<W: Write> Assembler<W> {
+    // Writes the words up to the first one still waiting for a symbol
+    fn flush(&mut self) -> io::Result<()> {
+        let ready = match self.unresolved.first() {
+            Some(at) => at - self.written,
+            None => self.words.len(),
+        };
+        if ready == 0 {
+            return Ok(());
+        }
+        self.format.write(&self.words[..ready], &mut self.out)?;
+        self.words.drain(..ready);
+        self.written += ready;
+        Ok(())
+    }
+
+    fn push(&mut self, word: u16) {
+        if self.word_count < ROM_SIZE {
+            self.words.push(word);
+        }
+        self.word_count += 1;
+    }
+
+    fn label(&mut self, code: &str, line_num: usize) -> Result<(), SpanError> {
+        let symbol = parse_label(code)?;
+        let len = symbol.chars().count();
+        let symbol = qualify(&symbol, &self.scope);
+        self.define(symbol, self.word_count, line_num, len, true)
+    }
+
+    // `.equ NAME value`, the only directive that works on a stream
+    fn directive(&mut self, raw: &str, line_num: usize) -> Result<(), SpanError> {
+        let fields: Vec<&str> = raw.split_whitespace().collect();
+        let len = raw.trim().chars().count();
+        if fields[0] != ".equ" {
+            return Err(SpanError::new(
+                format!(
+                    "`{}` isn't supported when streaming, assemble without --stream",
+                    fields[0]
+                ),
+                0,
+                fields[0].chars().count(),
+            ));
+        }
+        match (fields.get(1), fields.get(2).map(|v| parse_constant(v))) {
+            (Some(name), Some(Ok(value))) if fields.len() == 3 && is_identifier(name) => {
+                let name_len = name.chars().count();
+                // `.FOO` is scoped like a label, as in macros.rs
+                let name = qualify(name, &self.scope);
+                self.define(name, value as usize, line_num, name_len, false)
+                    .map_err(|e| SpanError::new(e.message, ".equ".len(), name_len))
+            }
+            (_, Some(Err(e))) => Err(SpanError::new(e, 0, len)),
+            _ => Err(SpanError::new(
+                "expected `.equ NAME value`".to_string(),
+                0,
+                len,
+            )),
+        }
+    }
+
+    // Adds a label or .equ constant and patches every earlier use of it
+    fn define(
+        &mut self,
+        symbol: String,
+        addr: usize,
+        line_num: usize,
+        len: usize,
+        is_label: bool,
+    ) -> Result<(), SpanError> {
+        let kind = if is_label { "label" } else { "constant" };
+        if self.predefined.contains_key(&symbol) {
+            return Err(SpanError::new(
+                format!("{} `{}` shadows the predefined symbol", kind, symbol),
+                1,
+                len,
+            ));
+        }
+        if let Some(first) = self.defined_on.get(&symbol) {
+            return Err(SpanError::new(
+                format!("`{}` is already defined on line {}", symbol, first),
+                1,
+                len,
+            ));
+        }
+        if is_label && addr == ROM_SIZE {
+            return Err(SpanError::new(
+                format!(
+                    "label `{}` is past the end of ROM, an A instruction can't load {}",
+                    symbol, addr
+                ),
+                1,
+                len,
+            ));
+        }
+        if addr > MAX_CONSTANT as usize {
+            // past the end of an overfull ROM, already reported
+            return Ok(());
+        }
+
+        let addr = addr as u16;
+        if let Some(forward) = self.forward.remove(&symbol) {
+            for at in forward.uses {
+                self.words[at - self.written] = addr;
+                self.unresolved.remove(&at);
+            }
+        }
+        self.defined_on.insert(symbol.clone(), line_num);
+        if is_label {
+            self.labels.insert(symbol.clone(), addr);
+        }
+        self.symbols.insert(symbol, addr);
+        Ok(())
+    }
+
+    fn a_instruction(&mut self, code: &str, line_num: usize, text: &str) -> Result<(), SpanError> {
+        self.last_a = None;
+        let symbol = qualify(&parse_a_instruction(code)?, &self.scope);
+        if is_constant(&symbol) {
+            let value = parse_constant(&symbol)
+                .map_err(|e| SpanError::new(e, 1, symbol.chars().count()))?;
+            self.push(value);
+            return Ok(());
+        }
+        if !self.predefined.contains_key(&symbol) {
+            let used = Use {
+                line_num,
+                source: text.to_string(),
+                // the source text may be a shorter `.local` name
+                len: code.chars().count() - 1,
+            };
+            self.last_a = Some((symbol.clone(), used));
+        }
+        if let Some(addr) = self.symbols.get(&symbol) {
+            self.push(*addr);
+            return Ok(());
+        }
+
+        // patched by define or allocate_variables
+        if self.word_count < ROM_SIZE {
+            let at = self.word_count;
+            self.unresolved.insert(at);
+            let forward = self.forward.entry(symbol.clone()).or_insert_with(|| {
+                self.first_use.push(symbol);
+                Forward {
+                    uses: Vec::new(),
+                    line_num,
+                    source: text.to_string(),
+                }
+            });
+            forward.uses.push(at);
+        }
+        self.push(0);
+        Ok(())
+    }
+
+    fn c_instruction(&mut self, code: &str) -> Result<(), SpanError> {
+        let c_instruct = parse_c_instruction(code)?;
+        let instruction = handle_c_instruction(&c_instruct, self.options.extended)?;
+        if let Some((symbol, used)) = self.last_a.take()
+            && let Instruction::C { comp, dest, jump } = &instruction
+        {
+            let usage = self.usages.entry(symbol).or_default();
+            if *jump != Jump::Null {
+                usage.jump.get_or_insert(used);
+            } else if comp.reads_m() || dest.writes_m() {
+                usage.data.get_or_insert(used);
+            }
+        }
+        self.push(instruction.encode());
+        Ok(())
+    }
+
+    fn allocate_variables(&mut self) {
+        let mut next = 16;
+        for symbol in mem::take(&mut self.first_use) {
+            let Some(forward) = self.forward.remove(&symbol) else {
+                continue;
+            };
+            if next == VARIABLE_END {
+                let err = SpanError::new(
+                    format!(
+                        "variable `{}` would be allocated at RAM {}, inside the screen memory map, only {} variables fit",
+                        symbol,
+                        VARIABLE_END,
+                        VARIABLE_END - 16
+                    ),
+                    1,
+                    symbol.chars().count(),
+                );
+                let (_, cols) = remove_all_whitespace(&forward.source);
+                self.errors
+                    .push(forward.line_num, &forward.source, &cols, err);
+            }
+            for at in forward.uses {
+                self.words[at - self.written] = next;
+                self.unresolved.remove(&at);
+            }
+            self.variables.insert(symbol, next);
+            next = next.saturating_add(1);
+        }
+    }
+
+    // The same warnings as the two pass assembler gives
+    fn check_symbol_usage(&mut self) {
+        let mut names: Vec<&String> = self
+            .usages
+            .keys()
+            .filter(|name| self.labels.contains_key(*name) || self.variables.contains_key(*name))
+            .collect();
+        names.sort();
+        for name in names {
+            let usage = &self.usages[name];
+            let Some(jump) = &usage.jump else {
+                continue;
+            };
+            let (used, message) = if self.variables.contains_key(name) {
+                (jump, jumps_to_variable(name))
+            } else if let Some(data) = &usage.data {
+                (data, jumps_and_data(name, jump.line_num))
+            } else {
+                continue;
+            };
+            let (_, cols) = remove_all_whitespace(&used.source);
+            let err = SpanError::new(message, 1, used.len);
+            self.errors.warn(used.line_num, &used.source, &cols, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::RefCell;
+    use std::fs;
+    use std::io::BufReader;
+    use std::io::Read;
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use crate::assemble;
+
+    fn stream(source: &str) -> Result<(Streamed, Vec<u8>), AsmError> {
+        let mut out = Vec::new();
+        let streamed = assemble_stream(
+            source.as_bytes(),
+            OutputFormat::Hack,
+            &mut out,
+            "test.asm",
+            AsmOptions::default(),
+        )?;
+        Ok((streamed, out))
+    }
+
+    #[test]
+    fn pong_comes_out_as_the_two_pass_assembler_writes_it() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../pong/Pong.asm");
+        let source = fs::read_to_string(path).unwrap();
+        same_as_two_pass(&source);
+    }
+
+    #[test]
+    fn constants_come_out_as_the_two_pass_assembler_writes_them() {
+        same_as_two_pass(".equ FOO 7\n.equ .BAR 3\n@.BAR\nD=A\n@FOO\nM=D\n@x\nM=D\n");
+    }
+
+    fn same_as_two_pass(source: &str) {
+        let program = assemble(source).unwrap();
+        let (streamed, out) = stream(source).unwrap();
+        assert_eq!(out, OutputFormat::Hack.encode(&program.words));
+        assert_eq!(streamed.size, program.words.len());
+        assert_eq!(streamed.variables, program.variables);
+    }
+
+    // Hands out one line per read and notes how many words were written by
+    // then
+    struct Lines {
+        lines: Vec<&'static str>,
+        out: Rc<RefCell<Vec<u8>>>,
+        seen: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl Read for Lines {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let written = self.out.borrow().iter().filter(|b| **b == b'\n').count();
+            self.seen.borrow_mut().push(written);
+            if self.lines.is_empty() {
+                return Ok(0);
+            }
+            let line = format!("{}\n", self.lines.remove(0));
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn words_are_written_once_resolved() {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let lines = Lines {
+            lines: vec![
+                "@1", "D=A", "@SKIP", "D;JGT", "(SKIP)", "@2", "D=A", "@x", "M=D", "@3",
+            ],
+            out: out.clone(),
+            seen: seen.clone(),
+        };
+        assemble_stream(
+            BufReader::new(lines),
+            OutputFormat::Hack,
+            Shared(out.clone()),
+            "test.asm",
+            AsmOptions::default(),
+        )
+        .unwrap();
+        // @SKIP waits for its label and @x for the end of the input
+        assert_eq!(*seen.borrow(), [0, 1, 2, 2, 2, 4, 5, 6, 6, 6, 6]);
+        assert_eq!(out.borrow().len(), 9 * 17);
+    }
+
+    #[test]
+    fn symbol_usage_is_checked() {
+        let source = "@LOOP\n0;JMP\n(DATA)\n@DATA\nD;JGT\n@DATA\nM=0\n";
+        let (streamed, _) = stream(source).unwrap();
+        let messages: Vec<&str> = streamed
+            .warnings
+            .iter()
+            .map(|w| w.message.as_str())
+            .collect();
+        let program = assemble(source).unwrap();
+        let expected: Vec<&str> = program
+            .warnings
+            .iter()
+            .map(|w| w.message.as_str())
+            .collect();
+        assert_eq!(messages, expected);
+        assert_eq!(messages.len(), 2);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::Program;
+use crate::SymbolTable;
+use crate::error::AsmError;
+use crate::error::SpanError;
+use crate::init_symbol_table;
+
+// The assembler's symbol table split by where the symbol lives
+#[derive(Debug, Default, Clone)]
+pub struct SymbolMap {
+    pub predefined: SymbolTable,
+    // ROM addresses
+    pub labels: SymbolTable,
+    // RAM addresses from 16 upward
+    pub variables: SymbolTable,
+    // from .equ
+    pub constants: SymbolTable,
+}
+
+impl SymbolMap {
+    pub fn from_program(program: &Program) -> Self {
+        Self {
+            predefined: init_symbol_table(),
+            labels: program.labels.clone(),
+            variables: program.variables.clone(),
+            constants: program.constants.clone(),
+        }
+    }
+
+    // only the builtin names, used when there is no .sym file
+    pub fn predefined() -> Self {
+        Self {
+            predefined: init_symbol_table(),
+            ..Default::default()
+        }
+    }
+
+    // One `kind name address` entry per line, sorted by kind then address
+    pub fn to_text(&self) -> String {
+        let mut sym = String::from("// kind name address\n");
+        for (kind, table) in self.kinds() {
+            for (name, addr) in sorted(table) {
+                sym.push_str(&format!("{} {} {}\n", kind, name, addr));
+            }
+        }
+        sym
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\n");
+        let kinds = self.kinds();
+        for (i, (kind, table)) in kinds.iter().enumerate() {
+            json.push_str(&format!("  \"{}\": {{", kind));
+            let entries: Vec<String> = sorted(table)
+                .into_iter()
+                .map(|(name, addr)| format!("\n    \"{}\": {}", escape_json(name), addr))
+                .collect();
+            json.push_str(&entries.join(","));
+            if !entries.is_empty() {
+                json.push_str("\n  ");
+            }
+            json.push('}');
+            if i + 1 < kinds.len() {
+                json.push(',');
+            }
+            json.push('\n');
+        }
+        json.push_str("}\n");
+        json
+    }
+
+    // Reads the text format written by to_text
+    pub fn parse_text(contents: &str) -> Result<SymbolMap, AsmError> {
+        let mut errors = AsmError::default();
+        let mut map = SymbolMap::default();
+        for (idx, line) in contents.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with("//") {
+                continue;
+            }
+            let fields: Vec<&str> = trimmed.split_whitespace().collect();
+            let addr = fields.get(2).and_then(|a| a.parse::<u16>().ok());
+            let table = match fields.first() {
+                Some(&"predefined") => Some(&mut map.predefined),
+                Some(&"label") => Some(&mut map.labels),
+                Some(&"variable") => Some(&mut map.variables),
+                Some(&"constant") => Some(&mut map.constants),
+                _ => None,
+            };
+            match (table, addr) {
+                (Some(table), Some(addr)) if fields.len() == 3 => {
+                    table.insert(fields[1].to_string(), addr);
+                }
+                _ => {
+                    let start = line.len() - line.trim_start().len();
+                    let cols: Vec<usize> = (start..start + trimmed.chars().count()).collect();
+                    let err = SpanError::new(
+                        format!(
+                            "expected `predefined|label|variable|constant name address`, found `{}`",
+                            trimmed
+                        ),
+                        0,
+                        cols.len(),
+                    );
+                    errors.push(idx + 1, line, &cols, err);
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(map)
+    }
+
+    fn kinds(&self) -> [(&'static str, &SymbolTable); 4] {
+        [
+            ("predefined", &self.predefined),
+            ("label", &self.labels),
+            ("variable", &self.variables),
+            ("constant", &self.constants),
+        ]
+    }
+}
+
+fn sorted(table: &SymbolTable) -> Vec<(&String, &u16)> {
+    let mut symbols: Vec<(&String, &u16)> = table.iter().collect();
+    symbols.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+    symbols
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::new();
+    for ch in s.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assemble;
+
+    fn map() -> SymbolMap {
+        let program =
+            assemble(".equ LIMIT 10\n@LIMIT\nD=A\n@count\nM=D\n(LOOP)\n@LOOP\n0;JMP\n").unwrap();
+        SymbolMap::from_program(&program)
+    }
+
+    #[test]
+    fn text_round_trips_through_parse_text() {
+        let map = map();
+        let text = map.to_text();
+        assert!(text.contains("\nlabel LOOP 4\nvariable count 16\nconstant LIMIT 10\n"));
+
+        let parsed = SymbolMap::parse_text(&text).unwrap();
+        assert_eq!(parsed.predefined, map.predefined);
+        assert_eq!(parsed.labels, map.labels);
+        assert_eq!(parsed.variables, map.variables);
+        assert_eq!(parsed.constants, map.constants);
+        assert_eq!(parsed.to_text(), text);
+    }
+
+    #[test]
+    fn json_holds_the_same_entries() {
+        let json = map().to_json();
+        assert!(json.starts_with("{\n  \"predefined\": {\n    \"R0\": 0,\n    \"SP\": 0,"));
+        assert!(json.ends_with(
+            "  \"label\": {\n    \"LOOP\": 4\n  },\n  \"variable\": {\n    \"count\": 16\n  },\n  \"constant\": {\n    \"LIMIT\": 10\n  }\n}\n"
+        ));
+        assert_eq!(escape_json("a\"b\\c\n"), "a\\\"b\\\\c\\u000a");
+        assert!(SymbolMap::default().to_json().contains("\"label\": {},"));
+    }
+
+    #[test]
+    fn bad_lines_are_reported() {
+        let errors =
+            SymbolMap::parse_text("label LOOP 4\n  label LOOP\nvariable x 70000\n").unwrap_err();
+        let lines: Vec<(usize, usize)> =
+            errors.diagnostics.iter().map(|d| (d.line, d.col)).collect();
+        assert_eq!(lines, [(2, 3), (3, 1)]);
+        assert_eq!(
+            errors.diagnostics[0].message,
+            "expected `predefined|label|variable|constant name address`, found `label LOOP`"
+        );
+    }
+}